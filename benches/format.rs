@@ -0,0 +1,45 @@
+use codize::{cblock, cconcat, clist, Code, Format, FormatCode};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// Generate `n` functions with nested blocks and lists
+fn generate(n: usize) -> Code {
+    let functions = (0..n).map(|i| {
+        cblock!(
+            format!("fn function_{i}(x: u32) -> u32 {{"),
+            [
+                Code::from(format!("let y = x + {i};")),
+                cblock!(
+                    "match y {",
+                    (0..8).map(|j| format!("{j} => {},", i * j)),
+                    "}"
+                ),
+                cblock!(
+                    "let v = vec![",
+                    [clist!("," => (0..16).map(|j| format!("{j}")))],
+                    "];"
+                ),
+                Code::from("v.len() as u32"),
+            ],
+            "}"
+        )
+    });
+    cconcat!(1 => functions).into()
+}
+
+fn bench_format(c: &mut Criterion) {
+    let mut group = c.benchmark_group("format");
+    for n in [10, 100, 1000] {
+        let code = generate(n);
+        let format = Format::default();
+        group.bench_with_input(BenchmarkId::new("string", n), &code, |b, code| {
+            b.iter(|| black_box(code.format_with(&format)))
+        });
+        group.bench_with_input(BenchmarkId::new("vec_join", n), &code, |b, code| {
+            b.iter(|| black_box(code.format_vec_with(&format).join("\n")))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_format);
+criterion_main!(benches);
@@ -0,0 +1,606 @@
+//! Code tree stored in flat vectors, with index handles instead of boxes
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::tree::{self, CodeRef, CodeTree, Tree};
+use crate::{
+    Block, Code, Concat, Connect, Emitter, Format, FormatCode, InlinePolicy, List, RenderContext,
+};
+
+/// Handle to a code node in a [`CodeArena`]
+///
+/// Handles can be used any number of times, in which case the node is emitted at each place.
+/// Using a handle with another arena panics or refers to a different node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CodeId(usize);
+
+/// Code tree stored in an arena, for generators with a very large number of nodes
+///
+/// All nodes are stored in one vector and the bodies in another, so building the tree
+/// does not allocate for each node, and dropping it does not walk the tree.
+/// The arena has the same builders as [`Block`], [`List`] and [`Concat`], and emits
+/// the same output as the [`Code`] it converts to.
+///
+/// Blocks and lists with custom inline conditions are converted to [`Code`] when emitted,
+/// so the conditions see their bodies. The other nodes are emitted from the arena, and are
+/// seen without their bodies in the ancestors of the context.
+///
+/// # Examples
+/// ```
+/// use codize::{cblock, Block, Code, CodeArena};
+///
+/// let mut arena = CodeArena::new();
+/// let call = arena.line("foo();");
+/// let body = arena.block("if (x) {", [call], "}");
+/// let main = arena.block("fn main() {", [body, call], "}");
+///
+/// let expected =
+/// "fn main() {
+///     if (x) { foo(); }
+///     foo();
+/// }";
+/// arena.update_block(body, Block::inlined);
+/// assert_eq!(expected, arena.get(main).to_string());
+///
+/// let code = cblock!("fn main() {", [cblock!("if (x) {", ["foo();"], "}").inlined(), "foo();"], "}");
+/// assert_eq!(Code::from(code), arena.to_code(main));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CodeArena {
+    nodes: Vec<Node>,
+    /// Bodies of all nodes. Each node refers to a range
+    children: Vec<CodeId>,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    kind: NodeKind,
+    /// Start of the body in the children
+    start: usize,
+    /// Length of the body
+    len: usize,
+}
+
+/// A node without the body
+#[derive(Debug, Clone)]
+enum NodeKind {
+    /// Code without children in the arena
+    Leaf(Code),
+    /// A block with empty body
+    Block(Block),
+    /// A list with empty body
+    List(List),
+    /// A concat with empty body
+    Concat(Concat),
+    /// A code connected with the mode. The body is the code
+    Connected(Connect),
+}
+
+impl CodeArena {
+    /// Create a new empty arena
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of nodes in the arena
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Get if the arena has no nodes
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Add a line of code. See [`Code::Line`]
+    pub fn line<T: Into<Cow<'static, str>>>(&mut self, line: T) -> CodeId {
+        self.push(NodeKind::Leaf(Code::Line(line.into())), [])
+    }
+
+    /// Add a line that must end its line. See [`Code::hard_line`]
+    pub fn hard_line<T: Into<Cow<'static, str>>>(&mut self, line: T) -> CodeId {
+        self.push(NodeKind::Leaf(Code::HardLine(line.into())), [])
+    }
+
+    /// Add a block of code. See [`Block::new`]
+    pub fn block<TStart, TBody, TEnd>(&mut self, start: TStart, body: TBody, end: TEnd) -> CodeId
+    where
+        TStart: Into<Cow<'static, str>>,
+        TEnd: Into<Cow<'static, str>>,
+        TBody: IntoIterator<Item = CodeId>,
+    {
+        let block = Block::from_parts(start, Concat::empty(), end);
+        self.push(NodeKind::Block(block), body)
+    }
+
+    /// Add a list of code segments. See [`List::new`]
+    pub fn list<TSep, TBody>(&mut self, sep: TSep, body: TBody) -> CodeId
+    where
+        TSep: Into<Cow<'static, str>>,
+        TBody: IntoIterator<Item = CodeId>,
+    {
+        self.push(NodeKind::List(List::from_parts(sep, Concat::empty())), body)
+    }
+
+    /// Add a concatenation of code sections. See [`Concat::new`]
+    pub fn concat<TBody>(&mut self, body: TBody) -> CodeId
+    where
+        TBody: IntoIterator<Item = CodeId>,
+    {
+        self.push(NodeKind::Concat(Concat::empty()), body)
+    }
+
+    /// Add a concatenation of code sections with blank lines between them.
+    /// See [`Concat::separated`]
+    pub fn separated<TBody>(&mut self, blank_lines: usize, body: TBody) -> CodeId
+    where
+        TBody: IntoIterator<Item = CodeId>,
+    {
+        self.push(
+            NodeKind::Concat(Concat::empty().blank_lines(blank_lines)),
+            body,
+        )
+    }
+
+    /// Add the code connected to the previous line with a space. See [`Code::connected`]
+    #[inline]
+    pub fn connected(&mut self, id: CodeId) -> CodeId {
+        self.connect_with(id, Connect::Space)
+    }
+
+    /// Add the code connected to the previous line with the mode. See [`Code::connect_with`]
+    ///
+    /// The code is wrapped in a new node, so other places using it are not affected
+    pub fn connect_with(&mut self, id: CodeId, mode: Connect) -> CodeId {
+        self.push(NodeKind::Connected(mode), [id])
+    }
+
+    /// Update a block with the [`Block`] builders, for example [`Block::inlined`].
+    /// This affects every place the block is used
+    ///
+    /// # Panics
+    /// Panics if the node is not a block
+    pub fn update_block<F: FnOnce(Block) -> Block>(&mut self, id: CodeId, f: F) {
+        let NodeKind::Block(block) = &mut self.nodes[id.0].kind else {
+            panic!("node is not a block");
+        };
+        let old = core::mem::replace(block, Block::empty("", ""));
+        *block = f(old);
+        // the body stays in the arena
+        block.concat_body.body.clear();
+    }
+
+    /// Update a list with the [`List`] builders, for example [`List::no_trail`].
+    /// This affects every place the list is used
+    ///
+    /// # Panics
+    /// Panics if the node is not a list
+    pub fn update_list<F: FnOnce(List) -> List>(&mut self, id: CodeId, f: F) {
+        let NodeKind::List(list) = &mut self.nodes[id.0].kind else {
+            panic!("node is not a list");
+        };
+        let old = core::mem::replace(list, List::empty(""));
+        *list = f(old);
+        list.concat_body.body.clear();
+    }
+
+    /// Add the code to the arena. Nested blocks, lists and concats are stored as nodes,
+    /// and other code is stored as-is
+    pub fn insert<T: Into<Code>>(&mut self, code: T) -> CodeId {
+        let root = CodeId(self.nodes.len());
+        // the code to add, and the index in the children to put its handle
+        let mut stack = vec![(code.into(), None)];
+        while let Some((code, slot)) = stack.pop() {
+            let id = CodeId(self.nodes.len());
+            if let Some(slot) = slot {
+                self.children[slot] = id;
+            }
+            let (kind, body) = match code {
+                Code::Block(mut block) => {
+                    let body = core::mem::take(&mut block.concat_body.body);
+                    (NodeKind::Block(*block), body)
+                }
+                Code::List(mut list) => {
+                    let body = core::mem::take(&mut list.concat_body.body);
+                    (NodeKind::List(list), body)
+                }
+                Code::Concat(mut concat) => {
+                    let body = core::mem::take(&mut concat.body);
+                    (NodeKind::Concat(concat), body)
+                }
+                Code::Connected(mode, code) => (NodeKind::Connected(mode), vec![*code]),
+                code => (NodeKind::Leaf(code), vec![]),
+            };
+            let start = self.children.len();
+            // the handles are filled in when the children are added
+            self.children.resize(start + body.len(), id);
+            self.nodes.push(Node {
+                kind,
+                start,
+                len: body.len(),
+            });
+            let slots = (start..start + body.len()).map(Some);
+            stack.extend(body.into_iter().zip(slots).rev());
+        }
+        root
+    }
+
+    /// Convert the node to [`Code`]
+    pub fn to_code(&self, id: CodeId) -> Code {
+        struct Frame<'a> {
+            id: CodeId,
+            iter: core::slice::Iter<'a, CodeId>,
+            out: Vec<Code>,
+        }
+        let new_frame = |id: CodeId| Frame {
+            id,
+            iter: self.body(id).iter(),
+            out: Vec::with_capacity(self.body(id).len()),
+        };
+        let mut stack = vec![new_frame(id)];
+        loop {
+            let frame = stack.last_mut().expect("root frame");
+            if let Some(&child) = frame.iter.next() {
+                stack.push(new_frame(child));
+                continue;
+            }
+            let frame = stack.pop().expect("frame");
+            let code = match &self.nodes[frame.id.0].kind {
+                NodeKind::Leaf(code) => code.clone(),
+                NodeKind::Block(block) => {
+                    let mut block = block.clone();
+                    block.concat_body.body = frame.out;
+                    block.into()
+                }
+                NodeKind::List(list) => {
+                    let mut list = list.clone();
+                    list.concat_body.body = frame.out;
+                    list.into()
+                }
+                NodeKind::Concat(concat) => {
+                    let mut concat = concat.clone();
+                    concat.body = frame.out;
+                    concat.into()
+                }
+                NodeKind::Connected(mode) => {
+                    let code = frame.out.into_iter().next().expect("connected code");
+                    Code::Connected(mode.clone(), Box::new(code))
+                }
+            };
+            match stack.last_mut() {
+                Some(parent) => parent.out.push(code),
+                None => return code,
+            }
+        }
+    }
+
+    /// Get the node for formatting. See [`FormatCode`]
+    #[inline]
+    pub fn get(&self, id: CodeId) -> ArenaCode<'_> {
+        ArenaCode { arena: self, id }
+    }
+
+    fn push<TBody>(&mut self, kind: NodeKind, body: TBody) -> CodeId
+    where
+        TBody: IntoIterator<Item = CodeId>,
+    {
+        let start = self.children.len();
+        self.children.extend(body);
+        self.nodes.push(Node {
+            kind,
+            start,
+            len: self.children.len() - start,
+        });
+        CodeId(self.nodes.len() - 1)
+    }
+
+    #[inline]
+    fn kind(&self, id: CodeId) -> &NodeKind {
+        &self.nodes[id.0].kind
+    }
+
+    #[inline]
+    fn body(&self, id: CodeId) -> &[CodeId] {
+        let node = &self.nodes[id.0];
+        &self.children[node.start..node.start + node.len]
+    }
+
+    /// Convert the blocks and lists with custom inline conditions in the node to [`Code`],
+    /// so the conditions see their bodies. Nodes used many times are only visited once
+    fn convert_custom(&self, id: CodeId) -> BTreeMap<CodeId, Code> {
+        let mut converted = BTreeMap::new();
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let custom = match self.kind(id) {
+                NodeKind::Block(block) => matches!(block.inline_policy, InlinePolicy::Custom(_)),
+                NodeKind::List(list) => matches!(list.inline_policy, InlinePolicy::Custom(_)),
+                _ => false,
+            };
+            if custom {
+                converted.insert(id, self.to_code(id));
+            } else {
+                stack.extend(self.body(id));
+            }
+        }
+        converted
+    }
+}
+
+/// A [`CodeArena`] as a [`Tree`], with some nodes converted to [`Code`] for the render
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ArenaTree<'s> {
+    arena: &'s CodeArena,
+    /// The nodes that are emitted from the converted code instead
+    converted: &'s BTreeMap<CodeId, Code>,
+}
+
+impl<'s> ArenaTree<'s> {
+    /// Get the reference to the node, or to its converted code
+    fn resolve(self, id: CodeId) -> ArenaRef<'s> {
+        match self.converted.get(&id) {
+            Some(code) => ArenaRef::Code(CodeRef::Code(code)),
+            None => ArenaRef::Node(id),
+        }
+    }
+}
+
+/// Reference to a code in a [`CodeArena`], for [`Tree`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ArenaRef<'a> {
+    /// A node in the arena
+    Node(CodeId),
+    /// A code inside a leaf node, such as the code in [`Code::Shared`]
+    Code(CodeRef<'a>),
+}
+
+impl<'s: 'a, 'a> Tree<'a> for ArenaTree<'s> {
+    type Id = ArenaRef<'a>;
+
+    fn node(self, id: ArenaRef<'a>) -> tree::Node<'a> {
+        let id = match id {
+            ArenaRef::Node(id) => id,
+            ArenaRef::Code(code) => return CodeTree.node(code),
+        };
+        match self.arena.kind(id) {
+            NodeKind::Leaf(code) => CodeTree::code_node(code),
+            NodeKind::Block(block) => tree::Node::Block(block),
+            NodeKind::List(list) => tree::Node::List(list),
+            NodeKind::Concat(concat) => tree::Node::Concat(concat),
+            NodeKind::Connected(mode) => tree::Node::Connected(mode),
+        }
+    }
+
+    fn len(self, id: ArenaRef<'a>) -> usize {
+        match id {
+            ArenaRef::Node(id) => match self.arena.kind(id) {
+                NodeKind::Leaf(code) => CodeTree::code_children(code).len(),
+                _ => self.arena.body(id).len(),
+            },
+            ArenaRef::Code(code) => CodeTree.len(code),
+        }
+    }
+
+    fn child(self, id: ArenaRef<'a>, index: usize) -> ArenaRef<'a> {
+        match id {
+            ArenaRef::Node(id) => match self.arena.kind(id) {
+                NodeKind::Leaf(code) => {
+                    ArenaRef::Code(CodeRef::Code(&CodeTree::code_children(code)[index]))
+                }
+                _ => self.resolve(self.arena.body(id)[index]),
+            },
+            ArenaRef::Code(code) => ArenaRef::Code(CodeTree.child(code, index)),
+        }
+    }
+}
+
+/// A node in a [`CodeArena`], for formatting
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaCode<'a> {
+    arena: &'a CodeArena,
+    id: CodeId,
+}
+
+impl<'s> ArenaCode<'s> {
+    /// Get the handle of the node
+    #[inline]
+    pub fn id(&self) -> CodeId {
+        self.id
+    }
+
+    /// Get the arena as a tree, without converted nodes
+    fn tree(&self) -> ArenaTree<'s> {
+        const NONE: &BTreeMap<CodeId, Code> = &BTreeMap::new();
+        ArenaTree {
+            arena: self.arena,
+            converted: NONE,
+        }
+    }
+}
+
+impl FormatCode for ArenaCode<'_> {
+    fn size_hint(&self) -> usize {
+        tree::size_hint(self.tree(), ArenaRef::Node(self.id))
+    }
+
+    fn flat_width(&self, format: &Format) -> usize {
+        crate::width::flat_width(self.tree(), ArenaRef::Node(self.id), format)
+    }
+
+    fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+        // the nodes are converted once for the render
+        let converted = self.arena.convert_custom(self.id);
+        let tree = ArenaTree {
+            arena: self.arena,
+            converted: &converted,
+        };
+        crate::render::emit_with(tree, tree.resolve(self.id), ctx, emitter, None);
+    }
+}
+
+impl core::fmt::Display for ArenaCode<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        crate::display(self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use alloc::string::ToString;
+
+    use indoc::indoc;
+
+    use crate::{cblock, cconcat, clist, Code};
+
+    use super::*;
+
+    fn samples() -> Vec<Code> {
+        vec![
+            cblock!("{", [], "}").into(),
+            cblock!(
+                "fn main() {",
+                [
+                    cblock!("if (foo) {", ["println!(\"Hello, world!\");"], "}"),
+                    cblock!("else {", [format!("bar({});", "giz")], "}").connected(),
+                ],
+                "}"
+            )
+            .into(),
+            cconcat!(1 => [
+                Code::hard_line("// header"),
+                cblock!("let x = [", [clist!("," => ["1", "2", "3"]).inlined()], "];"),
+                cblock!("let y = (", [cblock!("(", ["a"], ")")], ");"),
+                Code::from(";").connect_with(Connect::Tight),
+                cconcat![],
+                cblock!("do {", ["x++;"], "}"),
+                Code::from("while (x < 10);").connected(),
+                Code::from(cblock!("if (y) {", [clist!("," => ["a", "b"])], "}")).shared(),
+            ])
+            .into(),
+            clist!("," => [
+                Code::hard_line("// first"),
+                cblock!("{", ["a"], "}").inline_policy(InlinePolicy::MaxWidth(10)),
+                cblock!("{", [clist!("," => ["hello", "hello2"]).no_trail()], "}"),
+                cconcat!["a", Code::hard_line("// a")],
+                clist!("," => ["b", "c"]).inline_policy(InlinePolicy::AllChildrenInline),
+                clist!("," => ["d", "e", "f"]).inline_policy(InlinePolicy::MaxItems(2)),
+                Code::hard_line("// last"),
+            ])
+            .into(),
+            cblock!(
+                "call(",
+                [clist!("," => ["x", cblock!("y => {", ["z"], "}")]).always_trail()],
+                ")"
+            )
+            .never_inlined()
+            .into(),
+        ]
+    }
+
+    fn formats() -> Vec<Format> {
+        vec![
+            Format::default(),
+            Format::indent(2),
+            Format::indent_tab(),
+            Format::minify(),
+            Format::default().set_trim_blank_lines(true),
+        ]
+    }
+
+    #[test]
+    fn same_output() {
+        for code in samples() {
+            let mut arena = CodeArena::new();
+            let id = arena.insert(code.clone());
+            assert_eq!(code, arena.to_code(id));
+            let node = arena.get(id);
+            assert_eq!(code.size_hint(), node.size_hint());
+            for format in formats() {
+                assert_eq!(code.format_with(&format), node.format_with(&format));
+                assert_eq!(code.flat_width(&format), node.flat_width(&format));
+            }
+        }
+    }
+
+    #[test]
+    fn builders() {
+        let expected = indoc! {"
+            fn main() {
+                foo( a, b );
+
+                {
+                    a,
+                    b,
+                }
+                foo( a, b );
+            }"};
+        let mut arena = CodeArena::new();
+        let a = arena.line("a");
+        let b = arena.line("b");
+        let args = arena.list(",", [a, b]);
+        arena.update_list(args, |list| list.no_trail().inlined());
+        let call = arena.block("foo(", [args], ");");
+        arena.update_block(call, Block::inlined);
+        let items = arena.list(",", [a, b]);
+        let block = arena.block("{", [items], "}");
+        let body = arena.separated(1, [call, block]);
+        let body = arena.concat([body, call]);
+        let main = arena.block("fn main() {", [body], "}");
+        assert_eq!(expected, arena.get(main).to_string());
+        assert_eq!(expected, arena.to_code(main).to_string());
+        assert_eq!(9, arena.len());
+    }
+
+    #[test]
+    fn custom_inline_condition() {
+        // the condition sees the body, so only the block is converted when emitted
+        let block = cblock!("{", ["a"], "}")
+            .inline_when_in(|block, ctx| block.body().len() == 1 && ctx.parent().is_some());
+        let code: Code = clist!("," => [
+            cblock!("f(", [block.clone()], ")").inlined(),
+            block,
+            Code::from("b").shared(),
+        ])
+        .into();
+        let mut arena = CodeArena::new();
+        let id = arena.insert(code.clone());
+        assert_eq!("f( { a } ),\n{ a },\nb,", code.to_string());
+        assert_eq!(code.to_string(), arena.get(id).to_string());
+
+        let converted = arena.convert_custom(id);
+        assert_eq!(2, converted.len());
+        assert!(converted.values().all(|c| matches!(c, Code::Block(_))));
+        let shared = arena.block("{", [], "}");
+        arena.update_block(shared, |b| b.inline_when_in(|_, _| true));
+        let root = arena.concat([shared, shared, shared]);
+        assert_eq!(1, arena.convert_custom(root).len());
+    }
+
+    #[test]
+    fn deep() {
+        let depth = 100_000;
+        let mut arena = CodeArena::new();
+        let mut id = arena.line("x");
+        for _ in 0..depth {
+            let a = arena.line("a;");
+            id = arena.block("{", [a, id], "}");
+        }
+        let lines = arena.get(id).format_vec_with(&Format::indent(0));
+        assert_eq!(depth * 3 + 1, lines.len());
+        let code = arena.to_code(id);
+        let mut converted = CodeArena::new();
+        let id = converted.insert(code);
+        assert_eq!(lines, converted.get(id).format_vec_with(&Format::indent(0)));
+    }
+}
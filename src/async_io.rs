@@ -0,0 +1,231 @@
+//! Writing code to a [`tokio::io::AsyncWrite`]
+
+use alloc::string::String;
+use core::fmt;
+use std::io;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::render::Steps;
+use crate::{Code, Emitter, Format, Sink};
+
+/// Default size of the buffer for writing asynchronously
+const BUFFER_SIZE: usize = 8 * 1024;
+
+impl Code {
+    /// Emit the code with the format to an async writer, without building the whole output first.
+    ///
+    /// The output is the same as [`FormatCode::format_with`](crate::FormatCode::format_with).
+    /// The code is emitted in small steps into a buffer of about 8 KiB,
+    /// which is written to the writer whenever it is full.
+    /// The writer is flushed at the end
+    ///
+    /// # Examples
+    /// ```
+    /// use codize::{cblock, Code, Format};
+    ///
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// let code: Code = cblock!("fn main() {", ["println!(\"Hello, world!\");"], "}").into();
+    /// let mut out = Vec::new();
+    /// code.write_async(&Format::default(), &mut out).await.unwrap();
+    /// assert_eq!(code.to_string().as_bytes(), out);
+    /// # });
+    /// ```
+    pub async fn write_async<W>(&self, format: &Format, out: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        self.write_async_buffered(format, out, BUFFER_SIZE).await
+    }
+
+    /// Emit the code with the format to an async writer, using a buffer of about `buffer_size` bytes.
+    ///
+    /// The buffer can grow past the size by the output of one step, which is at most one line
+    /// of text, or all of the output of [`Code::Custom`] code.
+    /// See [`write_async`](Self::write_async)
+    pub async fn write_async_buffered<W>(
+        &self,
+        format: &Format,
+        out: &mut W,
+        buffer_size: usize,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut steps = Steps::new(self, format);
+        let mut buffer = Buffer::default();
+        let mut state = Some(Emitter::new(format, &mut buffer).pause());
+        while let Some(paused) = state {
+            // the emitter is paused to check the buffer, and it is not kept across awaits
+            // since the sink is not `Send`
+            state = {
+                let mut emitter = Emitter::resume(format, &mut buffer, paused);
+                if steps.step(&mut emitter) {
+                    Some(emitter.pause())
+                } else {
+                    // writing to a string never fails
+                    let _ = emitter.finish();
+                    None
+                }
+            };
+            if state.is_none() || buffer.text.len() >= buffer_size {
+                out.write_all(buffer.text.as_bytes()).await?;
+                buffer.text.clear();
+            }
+        }
+        out.flush().await
+    }
+}
+
+/// Lines separated by `\n`, until they are written to the writer
+#[derive(Default)]
+struct Buffer {
+    text: String,
+    /// If a line is written before, including in previous buffers
+    started: bool,
+}
+
+impl Sink for Buffer {
+    fn write_line(&mut self, line: &mut String) -> fmt::Result {
+        if self.started {
+            self.text.push('\n');
+        }
+        self.started = true;
+        self.text.push_str(line);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+
+    use super::*;
+    use crate::{cblock, clist, Concat, FormatCode};
+
+    fn sample() -> Code {
+        Concat::separated(
+            1,
+            (0..100).map(|i| {
+                cblock!(
+                    format!("fn f{i}() {{"),
+                    [
+                        Code::from(""),
+                        Code::from(clist!("," => ["a", "b"])),
+                        Code::hard_line("// c"),
+                        cblock!("{", [], "}").connected(),
+                    ],
+                    "}"
+                )
+            }),
+        )
+        .into()
+    }
+
+    /// Writer that records the size of each write
+    #[derive(Default)]
+    struct Recorder {
+        out: Vec<u8>,
+        writes: Vec<usize>,
+        flushed: bool,
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.out.extend_from_slice(buf);
+            self.writes.push(buf.len());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushed = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn same_output() {
+        let code = sample();
+        let formats = [
+            Format::default(),
+            Format::indent(2).set_max_blank_lines(0),
+            Format::default().set_trim_blank_lines(true),
+            Format::minify(),
+        ];
+        for format in &formats {
+            for buffer_size in [0, 1, 100, BUFFER_SIZE] {
+                let mut out = Vec::new();
+                code.write_async_buffered(format, &mut out, buffer_size)
+                    .await
+                    .unwrap();
+                assert_eq!(code.format_with(format), String::from_utf8(out).unwrap());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded() {
+        let code = sample();
+        let mut out = Recorder::default();
+        code.write_async_buffered(&Format::default(), &mut out, 100)
+            .await
+            .unwrap();
+        assert!(out.flushed);
+        assert!(out.writes.len() > 10);
+        // lines in the sample are short
+        assert!(out.writes.iter().all(|n| *n < 120));
+        assert_eq!(code.to_string().into_bytes(), out.out);
+
+        let mut out = Recorder::default();
+        Code::from(Concat::empty())
+            .write_async(&Format::default(), &mut out)
+            .await
+            .unwrap();
+        assert!(out.out.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lazy_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let lazy = crate::Lazy::new({
+            let count = Arc::clone(&count);
+            move || {
+                count.fetch_add(1, Ordering::SeqCst);
+                clist!("," => ["a", "b"])
+            }
+        });
+        let code: Code =
+            Concat::new((0..10).map(|i| cblock!(format!("fn f{i}() {{"), [lazy.clone()], "}")))
+                .into();
+        let mut out = Vec::new();
+        code.write_async_buffered(&Format::default(), &mut out, 1)
+            .await
+            .unwrap();
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        assert_eq!(code.to_string(), String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn send() {
+        fn assert_send<T: Send>(_: T) {}
+        let code = sample();
+        let format = Format::default();
+        let mut out = Vec::new();
+        assert_send(code.write_async(&format, &mut out));
+    }
+}
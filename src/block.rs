@@ -1,21 +1,33 @@
-use crate::{Code, Concat, Format, FormatCode};
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use crate::inline::{InlineTarget, Summaries};
+use crate::tree::CodeTree;
+use crate::{
+    Code, Concat, Connect, Emitter, Format, FormatCode, InlinePolicy, NodeRef, RenderContext,
+};
 
 /// A block of code with a starting line, ending line, and an indented body
-#[derive(derivative::Derivative)]
-#[derivative(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Block {
-    /// If this block should be connected to the end of a previous block
+    /// How this block is connected to the end of a previous block
     /// (for example, `else {`)
-    pub connect: bool,
+    pub connect: Connect,
     /// The start of the block (for example, `if (x) {`)
-    pub start: String,
+    pub start: Cow<'static, str>,
     /// The end of the block (for example, `}`)
-    pub end: String,
+    pub end: Cow<'static, str>,
     /// The body of the block. Usually the body is the part that gets indented
-    concat_body: Concat,
+    #[cfg_attr(feature = "serde", serde(rename = "body"))]
+    pub(crate) concat_body: Concat,
+    /// Don't emit the end line
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) no_end: bool,
     /// When to inline
-    #[derivative(Debug = "ignore", PartialEq = "ignore")]
-    inline_condition: Option<fn(&Block) -> bool>,
+    pub(crate) inline_policy: InlinePolicy<Block>,
 }
 
 impl Block {
@@ -25,69 +37,166 @@ impl Block {
         TStart: ToString,
         TEnd: ToString,
     {
-        Self {
-            connect: false,
-            start: start.to_string(),
-            concat_body: Concat::empty(),
-            end: end.to_string(),
-            inline_condition: None,
-        }
+        Self::from_parts(start.to_string(), Concat::empty(), end.to_string())
     }
 
     /// Create a new code block
+    ///
+    /// The body is taken from any iterator. Blocks are not collected with [`FromIterator`],
+    /// since the start and end are needed too
     pub fn new<TStart, TBody, TEnd>(start: TStart, body: TBody, end: TEnd) -> Self
     where
         TStart: ToString,
         TEnd: ToString,
         TBody: IntoIterator,
         TBody::Item: Into<Code>,
+    {
+        Self::from_parts(start.to_string(), Concat::new(body), end.to_string())
+    }
+
+    /// Create a new code block with static start and end, which are stored without allocating
+    pub fn borrowed<TBody>(start: &'static str, body: TBody, end: &'static str) -> Self
+    where
+        TBody: IntoIterator,
+        TBody::Item: Into<Code>,
+    {
+        Self::from_parts(start, Concat::new(body), end)
+    }
+
+    /// Create a block from the start, body and end as they are stored
+    pub(crate) fn from_parts<TStart, TEnd>(start: TStart, body: Concat, end: TEnd) -> Self
+    where
+        TStart: Into<Cow<'static, str>>,
+        TEnd: Into<Cow<'static, str>>,
     {
         Self {
-            connect: false,
-            start: start.to_string(),
-            concat_body: Concat::new(body),
-            end: end.to_string(),
-            inline_condition: None,
+            connect: Connect::Auto,
+            start: start.into(),
+            concat_body: body,
+            end: end.into(),
+            no_end: false,
+            inline_policy: InlinePolicy::Intrinsic,
         }
     }
 
     /// Set this block to start on the same line as the end of the previous block
-    pub fn connected(mut self) -> Self {
-        self.connect = true;
+    #[inline]
+    pub fn connected(self) -> Self {
+        self.connect_with(Connect::Space)
+    }
+
+    /// Set how this block is connected to the end of the previous block. See [`Connect`]
+    pub fn connect_with(mut self, mode: Connect) -> Self {
+        self.connect = mode;
         self
     }
 
-    /// Set a condition for displaying the block as one line
-    pub fn inline_when(mut self, condition: fn(&Block) -> bool) -> Self {
-        self.inline_condition = Some(condition);
+    /// Don't emit the end of the block, for languages where the indentation ends the block
+    /// (for example, Python). The code after the block starts on the next line
+    pub fn without_end(mut self) -> Self {
+        self.no_end = true;
         self
     }
 
+    /// Get if the end of the block is emitted. See [`without_end`](Self::without_end)
+    #[inline]
+    pub fn has_end(&self) -> bool {
+        !self.no_end
+    }
+
+    /// Set a condition for displaying the block as one line
+    #[inline]
+    pub fn inline_when<F>(self, condition: F) -> Self
+    where
+        F: Fn(&Block) -> bool + Send + Sync + 'static,
+    {
+        self.inline_when_in(move |x, _| condition(x))
+    }
+
+    /// Set a condition for displaying the block as one line, which can depend on the context
+    /// where the block is emitted. See [`RenderContext`]
+    #[inline]
+    pub fn inline_when_in<F>(self, condition: F) -> Self
+    where
+        F: Fn(&Block, &RenderContext<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.inline_policy(InlinePolicy::custom(condition))
+    }
+
     /// Set the inline condition to be always true
-    pub fn inlined(mut self) -> Self {
-        self.inline_condition = Some(|_| true);
-        self
+    #[inline]
+    pub fn inlined(self) -> Self {
+        self.inline_policy(InlinePolicy::Always)
     }
 
     /// Set the inline condition to be always false
-    pub fn never_inlined(mut self) -> Self {
-        self.inline_condition = Some(|_| false);
+    #[inline]
+    pub fn never_inlined(self) -> Self {
+        self.inline_policy(InlinePolicy::Never)
+    }
+
+    /// Set when to display the block as one line. See [`InlinePolicy`]
+    pub fn inline_policy(mut self, policy: InlinePolicy<Block>) -> Self {
+        self.inline_policy = policy;
         self
     }
 
+    /// Get when to display the block as one line
+    #[inline]
+    pub fn get_inline_policy(&self) -> &InlinePolicy<Block> {
+        &self.inline_policy
+    }
+
     /// Get the body of the block
     #[inline]
     pub fn body(&self) -> &[Code] {
         &self.concat_body
     }
 
+    /// Get the body of the block for changing it
+    #[inline]
+    pub fn body_mut(&mut self) -> &mut Vec<Code> {
+        &mut self.concat_body
+    }
+
+    /// Add code to the end of the body
+    #[inline]
+    pub fn push<T: Into<Code>>(&mut self, code: T) {
+        self.concat_body.push(code.into());
+    }
+
+    /// Insert code into the body at the index
+    ///
+    /// # Panics
+    /// If the index is greater than the length of the body
+    #[inline]
+    pub fn insert<T: Into<Code>>(&mut self, index: usize, code: T) {
+        self.concat_body.insert(index, code.into());
+    }
+
+    /// Only keep the code in the body for which the function returns true
+    #[inline]
+    pub fn retain<F: FnMut(&Code) -> bool>(&mut self, f: F) {
+        self.concat_body.retain(f);
+    }
+
     /// Should the block be displayed in one line
+    ///
+    /// Always false if the body contains a hard break. See [`Code::HardLine`]
     pub fn should_inline(&self) -> bool {
-        if let Some(condition) = self.inline_condition {
-            condition(self)
-        } else {
-            self.should_inline_intrinsic()
-        }
+        self.should_inline_in(&RenderContext::new(&Format::default()))
+    }
+
+    /// Should the block be displayed in one line, when emitted in the context
+    ///
+    /// Always false if the body contains a hard break. See [`Code::HardLine`]
+    pub fn should_inline_in(&self, ctx: &RenderContext<'_>) -> bool {
+        crate::inline::should_inline(
+            CodeTree,
+            NodeRef::Block(self).into(),
+            ctx,
+            &mut Summaries::default(),
+        )
     }
 
     /// Should intrinsicly inline the block
@@ -96,6 +205,22 @@ impl Block {
     pub fn should_inline_intrinsic(&self) -> bool {
         self.body().len() == 1 && self.body()[0].should_inline()
     }
+
+    /// Get if the body contains a hard break. See [`Code::HardLine`]
+    #[inline]
+    pub fn has_hard_break(&self) -> bool {
+        self.concat_body.has_hard_break()
+    }
+
+    /// Byte size hint of the start and end at the depth, without the body
+    pub(crate) fn own_byte_size_hint(&self, depth: usize, format: &Format) -> usize {
+        let line = crate::tree::line_byte_size(depth, format);
+        self.connect.byte_size_hint()
+            + self.start.len()
+            + self.end.len()
+            + 2 * line
+            + self.concat_body.own_size_hint()
+    }
 }
 
 impl From<Block> for Code {
@@ -104,47 +229,47 @@ impl From<Block> for Code {
     }
 }
 
-impl std::fmt::Display for Block {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.format())
+impl<T: Into<Code>> Extend<T> for Block {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.concat_body.extend(iter);
+    }
+}
+
+impl core::fmt::Display for Block {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        crate::display(self, f)
+    }
+}
+
+impl InlineTarget for Block {
+    #[inline]
+    fn node(&self) -> NodeRef<'_> {
+        NodeRef::Block(self)
+    }
+
+    fn inline_offset(&self, format: &Format) -> usize {
+        match format.width(&self.start) {
+            0 => 0,
+            n => n + 1,
+        }
     }
 }
 
 impl FormatCode for Block {
     fn size_hint(&self) -> usize {
-        // add the body, start, and end
-        self.concat_body.size_hint() + 2
-    }
-
-    fn format_into_vec_with(
-        &self,
-        format: &Format,
-        out: &mut Vec<String>,
-        connect: bool,
-        indent: &str,
-    ) {
-        let connect = self.connect || connect;
-        crate::append_line(out, &self.start, connect, indent);
-        let should_inline = self.should_inline();
-
-        if should_inline {
-            for code in self.body() {
-                code.format_into_vec_with(format, out, true, indent);
-            }
-        } else {
-            // indent the body
-            let i = format.indent;
-            let new_indent = if i < 0 {
-                format!("\t{indent}")
-            } else {
-                let i = i as usize;
-                format!("{:i$}{indent}", "")
-            };
-            for code in self.body() {
-                code.format_into_vec_with(format, out, false, &new_indent);
-            }
-        }
-        crate::append_line(out, &self.end, should_inline, indent);
+        crate::tree::size_hint(CodeTree, NodeRef::Block(self).into())
+    }
+
+    fn byte_size_hint(&self, format: &Format) -> usize {
+        self.own_byte_size_hint(0, format) + crate::tree::byte_size_hint(self.body(), 1, format)
+    }
+
+    fn flat_width(&self, format: &Format) -> usize {
+        crate::width::flat_width(CodeTree, NodeRef::Block(self).into(), format)
+    }
+
+    fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+        crate::render::emit(NodeRef::Block(self).into(), ctx, emitter)
     }
 }
 
@@ -211,6 +336,9 @@ macro_rules! cblock {
 
 #[cfg(test)]
 mod test {
+    use alloc::string::ToString;
+    use alloc::vec;
+
     use indoc::indoc;
 
     #[test]
@@ -320,4 +448,56 @@ mod test {
             }"};
         assert_eq!(expected, code.to_string());
     }
+
+    #[test]
+    fn without_end() {
+        let code = cblock! {
+            "{",
+            [
+                cblock!("if xxx:", ["foo()", "bar()"], "").without_end(),
+                cblock!("else:", ["baz()"], "").never_inlined().without_end(),
+                "qux()",
+            ],
+            "}"
+        };
+        let expected = indoc! {"
+            {
+                if xxx:
+                    foo()
+                    bar()
+                else:
+                    baz()
+                qux()
+            }"};
+        assert_eq!(expected, code.to_string());
+        assert_eq!(8, crate::FormatCode::size_hint(&code));
+
+        let code = cblock!("[", ["1"], "]").inlined().without_end();
+        assert!(!code.has_end());
+        assert_eq!("[ 1", code.to_string());
+        assert_eq!(
+            3,
+            crate::FormatCode::flat_width(&code, &crate::Format::default())
+        );
+    }
+
+    #[test]
+    fn mutate_body() {
+        let mut code = cblock!("impl Foo {", [], "}");
+        code.push("fn b() {}");
+        code.insert(0, "fn a() {}");
+        code.extend(["fn c() {}", "fn d() {}"]);
+        code.retain(|c| c != &crate::Code::from("fn c() {}"));
+        code.body_mut().swap(0, 1);
+        let expected = indoc! {"
+            impl Foo {
+                fn b() {}
+                fn a() {}
+                fn d() {}
+            }"};
+        assert_eq!(expected, code.to_string());
+
+        let methods = ["b", "a", "d"].map(|name| alloc::format!("fn {name}() {{}}"));
+        assert_eq!(code, crate::Block::new("impl Foo {", methods, "}"));
+    }
 }
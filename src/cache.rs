@@ -0,0 +1,526 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+
+use crate::emit::{LineBuffer, Tail};
+use crate::tree::{CodeRef, CodeTree};
+use crate::{Block, Code, Emitter, Format, InlinePolicy, Measure, RenderContext, TextSink};
+
+/// Cache of rendered blocks, for re-rendering a tree that mostly stays the same
+///
+/// Blocks are fingerprinted by hashing their content. The output of a block is cached keyed by
+/// the fingerprint, the indentation depth and the [`Format`], and reused when a block with the
+/// same fingerprint is rendered again, so only the blocks that changed are formatted.
+/// A hit is confirmed by comparing the block without its body, and the fingerprints of the
+/// items in the body, so only a collision of the 64-bit fingerprints of the items can
+/// reuse the wrong output.
+///
+/// A block is only cached when its output does not depend on the code around it:
+/// it starts on a new line, and it does not contain [`Code::Custom`] code or
+/// [`InlinePolicy::Custom`] policies. Nothing is cached when minifying.
+/// Blocks more than [`max_depth`](Self::set_max_depth) levels deep are formatted with their
+/// parent, so small blocks don't take up space in the cache.
+///
+/// # Examples
+/// ```
+/// use codize::{cblock, cconcat, Code, Format, RenderCache};
+///
+/// let mut cache = RenderCache::new();
+/// let format = Format::default();
+/// let code: Code = cconcat![
+///     cblock!("fn a() {", ["a();"], "}"),
+///     cblock!("fn b() {", ["b();"], "}"),
+/// ]
+/// .into();
+/// assert_eq!(code.to_string(), cache.format_with(&code, &format));
+/// assert_eq!(0, cache.stats().hits);
+///
+/// let code: Code = cconcat![
+///     cblock!("fn a() {", ["a();"], "}"),
+///     cblock!("fn b() {", ["c();"], "}"),
+/// ]
+/// .into();
+/// assert_eq!(code.to_string(), cache.format_with(&code, &format));
+/// assert_eq!(1, cache.stats().hits);
+/// ```
+#[derive(Debug)]
+pub struct RenderCache {
+    entries: HashMap<CacheKey, Entry>,
+    stats: CacheStats,
+    max_depth: usize,
+    /// Number of renders, to find the entries used by the last render
+    generation: u64,
+}
+
+/// Hit and miss counts of a [`RenderCache`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of blocks emitted from the cache
+    pub hits: usize,
+    /// Number of blocks formatted and added to the cache
+    pub misses: usize,
+}
+
+impl CacheStats {
+    /// Get the ratio of hits to lookups, or 0 if there are no lookups
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+impl Default for RenderCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RenderCache {
+    /// Create an empty cache
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            stats: CacheStats::default(),
+            max_depth: 4,
+            generation: 0,
+        }
+    }
+
+    /// Set the maximum indentation depth of the cached blocks. The default is 4
+    pub fn set_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Get the hit and miss counts since the cache is created or the stats are reset
+    #[inline]
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Reset the hit and miss counts
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// Get the number of cached blocks
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get if no block is cached
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove all cached blocks
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Remove the cached blocks that are not used by the last render,
+    /// such as the old versions of changed blocks
+    pub fn prune(&mut self) {
+        let generation = self.generation;
+        self.entries.retain(|_, entry| entry.used == generation);
+    }
+
+    /// Emit the code with the format as a string, reusing the output of unchanged blocks.
+    /// The output is the same as [`FormatCode::format_with`](crate::FormatCode::format_with)
+    pub fn format_with(&mut self, code: &Code, format: &Format) -> String {
+        crate::render::scoped(|| {
+            let mut out = String::with_capacity(crate::FormatCode::byte_size_hint(code, format));
+            let mut sink = TextSink::new(&mut out);
+            self.emit(code, format, &mut sink);
+            out
+        })
+    }
+
+    /// Emit the code with the format as a vector of lines, reusing the output of unchanged blocks.
+    /// The output is the same as [`FormatCode::format_vec_with`](crate::FormatCode::format_vec_with)
+    pub fn format_vec_with(&mut self, code: &Code, format: &Format) -> Vec<String> {
+        crate::render::scoped(|| {
+            let mut out = Vec::with_capacity(crate::FormatCode::size_hint(code));
+            self.emit(code, format, &mut out);
+            out
+        })
+    }
+
+    fn emit(&mut self, code: &Code, format: &Format, sink: &mut dyn crate::Sink) {
+        self.generation += 1;
+        let mut run = CacheRun {
+            cache: core::mem::take(self),
+            format: FormatKey::new(format),
+            fingerprints: fingerprints(code),
+        };
+        let mut emitter = Emitter::new(format, sink);
+        crate::render::emit_with(
+            CodeTree,
+            CodeRef::Code(code),
+            &RenderContext::new(format),
+            &mut emitter,
+            Some(&mut run),
+        );
+        // writing to a string or vector never fails
+        let _ = emitter.finish();
+        *self = run.cache;
+    }
+}
+
+/// State of the cache while rendering
+pub(crate) struct CacheRun {
+    cache: RenderCache,
+    format: FormatKey,
+    /// Fingerprints of the blocks that can be cached, by address
+    fingerprints: HashMap<usize, Fingerprint>,
+}
+
+/// Fingerprint of a block that can be cached
+#[derive(Debug)]
+struct Fingerprint {
+    /// Hash of the content of the block
+    hash: u64,
+    /// Hashes of the items in the body, to confirm a hit
+    children: Vec<u64>,
+}
+
+impl CacheRun {
+    /// Get the key of the block at the depth, if it can be cached
+    pub(crate) fn key(&self, block: &Block, depth: usize) -> Option<CacheKey> {
+        if depth > self.cache.max_depth {
+            return None;
+        }
+        let fingerprint = self.fingerprints.get(&core::ptr::from_ref(block).addr())?;
+        Some(CacheKey {
+            fingerprint: fingerprint.hash,
+            depth,
+            format: self.format.clone(),
+        })
+    }
+
+    /// Look up the output of a block. Entries with the same key but different content are misses
+    pub(crate) fn get(&mut self, key: &CacheKey, block: &Block) -> Option<&Entry> {
+        let generation = self.cache.generation;
+        let children = Self::children(&self.fingerprints, block);
+        match self.cache.entries.get_mut(key) {
+            Some(entry) if entry.block.shallow_eq(block) && entry.children == children => {
+                self.cache.stats.hits += 1;
+                entry.used = generation;
+                Some(entry)
+            }
+            _ => {
+                self.cache.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Add the output of a block
+    pub(crate) fn insert(&mut self, key: CacheKey, block: &Block, lines: LineBuffer, tail: Tail) {
+        let entry = Entry {
+            lines,
+            tail,
+            block: block.shallow_clone(),
+            children: Self::children(&self.fingerprints, block).to_vec(),
+            used: self.cache.generation,
+        };
+        self.cache.entries.insert(key, entry);
+    }
+
+    /// Get the fingerprints of the items in the body of a block that can be cached
+    fn children<'f>(fingerprints: &'f HashMap<usize, Fingerprint>, block: &Block) -> &'f [u64] {
+        let address = core::ptr::from_ref(block).addr();
+        fingerprints
+            .get(&address)
+            .map_or(&[], |fingerprint| &fingerprint.children)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    fingerprint: u64,
+    depth: usize,
+    format: FormatKey,
+}
+
+/// The output of a block, without the indentation of the parents
+#[derive(Debug)]
+pub(crate) struct Entry {
+    pub(crate) lines: LineBuffer,
+    /// The last line and the blank lines before it, which are not written
+    pub(crate) tail: Tail,
+    /// The block without the body, to confirm a hit
+    block: Block,
+    /// Fingerprints of the items in the body, to confirm a hit
+    children: Vec<u64>,
+    /// Generation of the last render that used the entry
+    used: u64,
+}
+
+/// The options of a [`Format`] that affect the output of blocks that are not minified
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FormatKey {
+    indent: i32,
+    tab_width: usize,
+    measure: Measure,
+    max_blank_lines: Option<usize>,
+    trim_blank_lines: bool,
+}
+
+impl FormatKey {
+    fn new(format: &Format) -> Self {
+        Self {
+            indent: format.indent,
+            tab_width: format.tab_width,
+            measure: format.measure.clone(),
+            max_blank_lines: format.max_blank_lines,
+            trim_blank_lines: format.trim_blank_lines,
+        }
+    }
+}
+
+/// Fingerprint the blocks in the code whose output only depends on their content.
+///
+/// The fingerprint of a node combines its own content with the fingerprints of the children,
+/// so the whole tree is hashed once
+fn fingerprints(code: &Code) -> HashMap<usize, Fingerprint> {
+    struct Frame<'a> {
+        code: &'a Code,
+        next: usize,
+        hasher: DefaultHasher,
+        cacheable: bool,
+        /// Fingerprints of the children, only kept for blocks
+        children: Vec<u64>,
+    }
+    fn frame(code: &Code) -> Frame<'_> {
+        let mut hasher = DefaultHasher::new();
+        code.shallow_hash(&mut hasher);
+        let cacheable = match code {
+            Code::Custom(_) => false,
+            Code::Block(block) => !matches!(block.inline_policy, InlinePolicy::Custom(_)),
+            Code::List(list) => !matches!(list.inline_policy, InlinePolicy::Custom(_)),
+            _ => true,
+        };
+        Frame {
+            code,
+            next: 0,
+            hasher,
+            cacheable,
+            children: Vec::new(),
+        }
+    }
+    let mut out = HashMap::new();
+    let mut stack = vec![frame(code)];
+    while let Some(top) = stack.last_mut() {
+        if let Some(child) = top.code.children().get(top.next) {
+            top.next += 1;
+            stack.push(frame(child));
+            continue;
+        }
+        let done = stack.pop().expect("frame");
+        let hash = done.hasher.finish();
+        if let (Code::Block(block), true) = (done.code, done.cacheable) {
+            let fingerprint = Fingerprint {
+                hash,
+                children: done.children,
+            };
+            out.insert(core::ptr::from_ref(&**block).addr(), fingerprint);
+        }
+        if let Some(parent) = stack.last_mut() {
+            parent.hasher.write_u64(hash);
+            parent.cacheable &= done.cacheable;
+            if let Code::Block(_) = parent.code {
+                parent.children.push(hash);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use alloc::string::ToString;
+
+    use indoc::indoc;
+
+    use super::*;
+    use crate::{cblock, cconcat, clist, Concat, Connect, FormatCode};
+
+    fn function(name: &str, body: &str) -> Code {
+        cblock!(
+            format!("fn {name}() {{"),
+            [
+                cblock!("if x {", [body.to_string()], "}"),
+                Code::from("").connected(),
+                cblock!("else {", ["return;"], "}").connected(),
+                Code::from(""),
+                clist!("," => ["a", "b"]),
+            ],
+            "}"
+        )
+        .into()
+    }
+
+    fn module(bodies: &[&str]) -> Code {
+        Concat::separated(
+            1,
+            bodies
+                .iter()
+                .enumerate()
+                .map(|(i, body)| function(&format!("f{i}"), body)),
+        )
+        .into()
+    }
+
+    #[test]
+    fn same_output() {
+        let formats = [
+            Format::default(),
+            Format::indent(2).set_max_blank_lines(0),
+            Format::default().set_trim_blank_lines(true),
+            Format::default().set_minify(true),
+        ];
+        let mut cache = RenderCache::new();
+        for format in &formats {
+            // lines of blocks can be collapsed, but they are still counted for the list
+            let list = clist!("," => [cblock!("", ["x"], "")]);
+            // the hard break at the end of the block is kept when replayed
+            let comment = cconcat![
+                cblock!("if x:", [Code::hard_line("# c")], "").without_end(),
+                Code::from("y").connected(),
+            ];
+            for code in [
+                module(&["a;", "b;", "c;"]),
+                module(&["a;", "d;"]),
+                list.into(),
+                comment.into(),
+            ] {
+                assert_eq!(code.format_with(format), cache.format_with(&code, format));
+                assert_eq!(
+                    code.format_vec_with(format),
+                    cache.format_vec_with(&code, format)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hit_rate() {
+        let mut cache = RenderCache::new();
+        let format = Format::default();
+        cache.format_with(&module(&["a;", "b;", "c;"]), &format);
+        // 3 functions and 6 blocks inside. The else blocks are connected
+        assert_eq!(CacheStats { hits: 0, misses: 6 }, *cache.stats());
+        assert_eq!(6, cache.len());
+
+        cache.reset_stats();
+        cache.format_with(&module(&["a;", "d;", "c;"]), &format);
+        // the changed function and its if block are formatted again
+        assert_eq!(CacheStats { hits: 2, misses: 2 }, *cache.stats());
+        assert_eq!(0.5, cache.stats().hit_rate());
+
+        cache.prune();
+        assert_eq!(4, cache.len());
+        cache.format_with(&module(&["a;", "d;", "c;"]), &Format::indent(2));
+        assert_eq!(10, cache.len());
+    }
+
+    #[test]
+    fn not_cached() {
+        let mut cache = RenderCache::new().set_max_depth(0);
+        let code: Code = cconcat![
+            cblock!("a {", ["b"], "}").connected(),
+            cblock!("f(", ["a"], ")").inline_policy(InlinePolicy::custom(|_, _| true)),
+            cblock!("g(", [cblock!("{", ["a"], "}")], ")"),
+            cblock!("{", [crate::Lazy::new(|| "c")], "}"),
+            cblock!("h(", ["a"], ")").connect_with(Connect::Space),
+        ]
+        .into();
+        let expected = indoc! {"
+            a {
+                b
+            }
+            f( a )
+            g(
+                {
+                    a
+                }
+            )
+            {
+                c
+            } h(
+                a
+            )"};
+        assert_eq!(expected, cache.format_with(&code, &Format::default()));
+        // only g is cached, the block inside is too deep
+        assert_eq!(1, cache.len());
+        assert_eq!(1, cache.stats().misses);
+    }
+
+    #[test]
+    fn collision() {
+        let format = Format::default();
+        let a: Code = cblock!("fn a() {", ["a();"], "}").into();
+        let mut cache = RenderCache::new();
+        cache.format_with(&a, &format);
+        let hash = fingerprints(&a).into_values().next().unwrap().hash;
+
+        // blocks with the fingerprint of `a` but different content are not taken from the cache
+        for code in [
+            cblock!("fn b() {", ["a();"], "}"),
+            cblock!("fn a() {", ["b();"], "}"),
+            cblock!("fn a() {", ["a();", "a();"], "}"),
+        ] {
+            let code = Code::from(code);
+            let mut run = CacheRun {
+                cache: core::mem::take(&mut cache),
+                format: FormatKey::new(&format),
+                fingerprints: fingerprints(&code),
+            };
+            for fingerprint in run.fingerprints.values_mut() {
+                fingerprint.hash = hash;
+            }
+            let mut out = String::new();
+            let mut sink = TextSink::new(&mut out);
+            let mut emitter = Emitter::new(&format, &mut sink);
+            let ctx = RenderContext::new(&format);
+            crate::render::emit_with(
+                CodeTree,
+                CodeRef::Code(&code),
+                &ctx,
+                &mut emitter,
+                Some(&mut run),
+            );
+            emitter.finish().unwrap();
+            assert_eq!(code.to_string(), out);
+            cache = run.cache;
+            assert_eq!(0, cache.stats().hits);
+            // the entry is replaced, so put `a` back
+            cache.format_with(&a, &format);
+        }
+        cache.format_with(&a, &format);
+        assert_eq!(1, cache.stats().hits);
+    }
+
+    #[test]
+    fn deep() {
+        let mut code = Code::from("x");
+        for _ in 0..500 {
+            code = cblock!("{", [code], "}").into();
+        }
+        let mut cache = RenderCache::new();
+        let format = Format::default();
+        assert_eq!(code.format_with(&format), cache.format_with(&code, &format));
+        assert_eq!(5, cache.len());
+        assert_eq!(code.format_with(&format), cache.format_with(&code, &format));
+        assert_eq!(1, cache.stats().hits);
+    }
+}
@@ -1,17 +1,30 @@
-use std::ops::{Deref, DerefMut};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use core::ops::{Deref, DerefMut};
 
-use crate::{Code, Format, FormatCode};
+use crate::tree::CodeTree;
+use crate::{Code, Emitter, Format, FormatCode, NodeRef, RenderContext};
 
 /// A concatenation of multiple code sections
-#[derive(Debug, Clone, PartialEq)]
+///
+/// Cloning, comparing, hashing and dropping do not recurse, so deeply nested code is safe
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Concat {
-    body: Vec<Code>,
+    pub(crate) body: Vec<Code>,
+    /// The number of blank lines to put between non-empty sections
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) blank_lines: usize,
 }
 
 impl Concat {
     /// Create a new empty concatenation of code sections
     pub fn empty() -> Self {
-        Self { body: vec![] }
+        Self {
+            body: vec![],
+            blank_lines: 0,
+        }
     }
 
     /// Create a new concatenation of code sections
@@ -22,14 +35,90 @@ impl Concat {
     {
         Self {
             body: body.into_iter().map(|code| code.into()).collect(),
+            blank_lines: 0,
         }
     }
 
+    /// Create a new concatenation of code sections, with `blank_lines` blank lines
+    /// between the non-empty sections
+    pub fn separated<TBody>(blank_lines: usize, body: TBody) -> Self
+    where
+        TBody: IntoIterator,
+        TBody::Item: Into<Code>,
+    {
+        Self::new(body).blank_lines(blank_lines)
+    }
+
+    /// Set the number of blank lines between the non-empty sections.
+    ///
+    /// Blank lines are not added before sections that are connected to the previous one
+    pub fn blank_lines(mut self, blank_lines: usize) -> Self {
+        self.blank_lines = blank_lines;
+        self
+    }
+
+    /// Get the number of blank lines between the non-empty sections
+    #[inline]
+    pub fn get_blank_lines(&self) -> usize {
+        self.blank_lines
+    }
+
     /// Get if the concat will generate any code or not (empty = no code)
     #[inline]
     pub fn is_empty(&self) -> bool {
         self.body.is_empty()
     }
+
+    /// Get if any section contains a hard break. See [`Code::HardLine`]
+    pub fn has_hard_break(&self) -> bool {
+        crate::tree::has_hard_break(CodeTree, NodeRef::Concat(self).into())
+    }
+
+    /// Get if the last non-empty section ends with a hard break. See [`Code::HardLine`]
+    pub fn ends_with_hard_break(&self) -> bool {
+        crate::tree::ends_with_hard_break(CodeTree, NodeRef::Concat(self).into())
+    }
+}
+
+impl Concat {
+    /// Size hint of the blank lines, without the sections
+    pub(crate) fn own_size_hint(&self) -> usize {
+        if self.blank_lines == 0 {
+            return 0;
+        }
+        let separators = self.body.iter().filter(|c| !c.is_empty()).count();
+        separators.saturating_sub(1) * self.blank_lines
+    }
+}
+
+impl Clone for Concat {
+    fn clone(&self) -> Self {
+        Self {
+            body: crate::tree::clone_all(&self.body),
+            blank_lines: self.blank_lines,
+        }
+    }
+}
+
+impl PartialEq for Concat {
+    fn eq(&self, other: &Self) -> bool {
+        self.blank_lines == other.blank_lines && crate::tree::eq_all(&self.body, &other.body)
+    }
+}
+
+impl Eq for Concat {}
+
+impl Hash for Concat {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.blank_lines.hash(state);
+        crate::tree::hash_all(&self.body, state);
+    }
+}
+
+impl Drop for Concat {
+    fn drop(&mut self) {
+        crate::tree::drop_all(core::mem::take(&mut self.body));
+    }
 }
 
 impl Deref for Concat {
@@ -48,6 +137,18 @@ impl DerefMut for Concat {
     }
 }
 
+impl<T: Into<Code>> Extend<T> for Concat {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.body.extend(iter.into_iter().map(Into::into));
+    }
+}
+
+impl<T: Into<Code>> FromIterator<T> for Concat {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Concat::new(iter)
+    }
+}
+
 impl From<Concat> for Code {
     #[inline]
     fn from(x: Concat) -> Self {
@@ -65,31 +166,27 @@ where
     }
 }
 
-impl std::fmt::Display for Concat {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.format())
+impl core::fmt::Display for Concat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        crate::display(self, f)
     }
 }
 
 impl FormatCode for Concat {
     fn size_hint(&self) -> usize {
-        self.body.iter().map(|code| code.size_hint()).sum()
-    }
-
-    fn format_into_vec_with(
-        &self,
-        format: &Format,
-        out: &mut Vec<String>,
-        connect: bool,
-        indent: &str,
-    ) {
-        let mut iter = self.body.iter();
-        if let Some(first) = iter.next() {
-            first.format_into_vec_with(format, out, connect, indent);
-        }
-        for code in iter {
-            code.format_into_vec_with(format, out, false, indent);
-        }
+        crate::tree::size_hint(CodeTree, NodeRef::Concat(self).into())
+    }
+
+    fn byte_size_hint(&self, format: &Format) -> usize {
+        self.own_size_hint() + crate::tree::byte_size_hint(&self.body, 0, format)
+    }
+
+    fn flat_width(&self, format: &Format) -> usize {
+        crate::width::flat_width(CodeTree, NodeRef::Concat(self).into(), format)
+    }
+
+    fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+        crate::render::emit(NodeRef::Concat(self).into(), ctx, emitter)
     }
 }
 
@@ -101,6 +198,9 @@ impl FormatCode for Concat {
 ///
 /// For 2 or more arguments, they are concatenated into a new [`Concat`] instance.
 ///
+/// Use `cconcat!(n => [...])` to put `n` blank lines between the non-empty sections.
+/// See [`Concat::separated`]
+///
 /// # Examples
 /// ```
 /// use codize::{cblock, cconcat};
@@ -123,11 +223,29 @@ impl FormatCode for Concat {
 ///     ], "}")
 /// ];
 /// assert_eq!(expected, code.to_string());
+///
+/// let code = cconcat!(1 => [
+///     cblock!("fn main() {", [
+///        "foo();",
+///     ], "}"),
+///     cconcat![], // empty sections are skipped
+///     cblock!("fn foo() {", [
+///         "bar();",
+///     ], "}")
+/// ]);
+/// assert_eq!(expected, code.to_string());
+/// ```
 #[macro_export]
 macro_rules! cconcat {
     () => {
         $crate::Concat::empty()
     };
+    ($n:literal => [ $( $body:expr ),* $(,)? ]) => {
+        $crate::Concat::separated($n, [ $($crate::Code::from($body)),* ])
+    };
+    ($n:literal => $body:expr) => {
+        $crate::Concat::separated($n, $body)
+    };
     ($body:expr)=> {
         $crate::Concat::new($body)
     };
@@ -138,6 +256,9 @@ macro_rules! cconcat {
 
 #[cfg(test)]
 mod test {
+    use alloc::string::ToString;
+    use alloc::vec;
+
     use crate::{cblock, Code, Concat};
 
     #[test]
@@ -170,4 +291,32 @@ mod test {
             ])
         );
     }
+
+    #[test]
+    fn separated() {
+        let code = cconcat!(2 => ["a", cconcat![], "b", "c"]);
+        assert_eq!(
+            code,
+            Concat::new([Code::from("a"), cconcat![].into(), "b".into(), "c".into()])
+                .blank_lines(2)
+        );
+        assert_ne!(
+            code,
+            Concat::new([Code::from("a"), cconcat![].into(), "b".into(), "c".into()])
+        );
+        assert_eq!("a\n\n\nb\n\n\nc", code.to_string());
+
+        let body = vec!["a", "b"];
+        let code = cconcat!(1 => body);
+        assert_eq!("a\n\nb", code.to_string());
+    }
+
+    #[test]
+    fn extend_collect() {
+        let mut code: Concat = ["a", "b"].into_iter().collect();
+        code.extend([cblock!("c {", ["d"], "}")]);
+        code.extend(["e".to_string()]);
+        assert_eq!(4, code.len());
+        assert_eq!("a\nb\nc {\n    d\n}\ne", code.to_string());
+    }
 }
@@ -0,0 +1,66 @@
+use alloc::borrow::Cow;
+
+use crate::Emitter;
+
+/// How a code segment is connected to the end of the previous line
+///
+/// Set it with [`Block::connect_with`](crate::Block::connect_with) on blocks,
+/// or [`Code::connect_with`](crate::Code::connect_with) on any code segment
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Connect {
+    /// Start on a new line, unless connected by the parent (for example, in an inlined block)
+    #[default]
+    Auto,
+    /// Connect with a space, for example `} else {` or `} while (x);`
+    Space,
+    /// Connect without a space, for example `})` or `}.then(`
+    Tight,
+    /// Connect with a custom joiner, for example `Connect::Join("; ".into())`
+    Join(Cow<'static, str>),
+    /// Always start on a new line, even if the parent connects it. For example, `else` on the
+    /// next line in Allman style
+    ///
+    /// This is ignored when minifying
+    NewLine,
+}
+
+impl Connect {
+    /// Get if the segment is always connected to the previous line
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Connect::Space | Connect::Tight | Connect::Join(_))
+    }
+
+    /// Apply the mode of a segment to the emitter before emitting it.
+    /// [`Connect::Auto`] keeps what the parent decided
+    pub(crate) fn apply(&self, emitter: &mut Emitter<'_>) {
+        match self {
+            Connect::Auto => {}
+            Connect::NewLine if emitter.format().minify => {}
+            mode => emitter.connect(mode),
+        }
+    }
+}
+
+impl Connect {
+    /// Number of bytes the joiner adds
+    #[inline]
+    pub(crate) fn byte_size_hint(&self) -> usize {
+        match self {
+            Connect::Join(joiner) => joiner.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl From<bool> for Connect {
+    /// `true` for [`Connect::Space`] and `false` for [`Connect::Auto`]
+    fn from(connect: bool) -> Self {
+        if connect {
+            Connect::Space
+        } else {
+            Connect::Auto
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use crate::{Block, Concat, Format, List};
+
+/// Context of where the code is being emitted
+///
+/// This is passed to [`FormatCode::emit`](crate::FormatCode::emit)
+/// and inline conditions, so the layout can depend on the depth, the indentation, the column
+/// or the parents
+#[derive(Debug, Clone, Copy)]
+pub struct RenderContext<'a> {
+    format: &'a Format,
+    depth: usize,
+    indent: &'a str,
+    column: usize,
+    /// Ancestors from the root, kept by the renderer
+    ancestors: &'a [NodeRef<'a>],
+    /// Ancestors linked while evaluating inline policies, below `ancestors`
+    link: Option<&'a Ancestor<'a>>,
+}
+
+/// Reference to a code structure that contains other code
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum NodeRef<'a> {
+    /// A block. See [`Block`]
+    Block(&'a Block),
+    /// A list. See [`List`]
+    List(&'a List),
+    /// A concatenation. See [`Concat`]
+    Concat(&'a Concat),
+}
+
+/// Linked list of ancestors, stored on the stack while evaluating inline policies
+#[derive(Debug)]
+pub(crate) struct Ancestor<'a> {
+    node: NodeRef<'a>,
+    parent: Option<&'a Ancestor<'a>>,
+}
+
+impl<'a> RenderContext<'a> {
+    /// Create a context for emitting code at the top level with the format
+    #[inline]
+    pub fn new(format: &'a Format) -> Self {
+        Self {
+            format,
+            depth: 0,
+            indent: "",
+            column: 0,
+            ancestors: &[],
+            link: None,
+        }
+    }
+
+    /// Create a context with the ancestors from the root
+    #[inline]
+    pub(crate) fn with_ancestors(
+        format: &'a Format,
+        depth: usize,
+        indent: &'a str,
+        column: usize,
+        ancestors: &'a [NodeRef<'a>],
+    ) -> Self {
+        Self {
+            format,
+            depth,
+            indent,
+            column,
+            ancestors,
+            link: None,
+        }
+    }
+
+    /// Get the format being used
+    #[inline]
+    pub fn format(&self) -> &'a Format {
+        self.format
+    }
+
+    /// Get the indentation level
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Get the indentation for new lines
+    #[inline]
+    pub fn indent(&self) -> &'a str {
+        self.indent
+    }
+
+    /// Get the column (display width from the start of the line) where the code starts
+    #[inline]
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Get the code structure that contains the code being emitted, if any
+    #[inline]
+    pub fn parent(&self) -> Option<NodeRef<'a>> {
+        match self.link {
+            Some(link) => Some(link.node),
+            None => self.ancestors.last().copied(),
+        }
+    }
+
+    /// Iterate over the code structures that contain the code being emitted,
+    /// starting from the parent
+    pub fn ancestors(&self) -> impl Iterator<Item = NodeRef<'a>> {
+        core::iter::successors(self.link, |a| a.parent)
+            .map(|a| a.node)
+            .chain(self.ancestors.iter().rev().copied())
+    }
+
+    /// Set the column where the code starts
+    #[inline]
+    pub(crate) fn with_column(self, column: usize) -> Self {
+        Self { column, ..self }
+    }
+
+    /// Create the ancestor link for emitting the children of `node`
+    #[inline]
+    pub(crate) fn link(&self, node: NodeRef<'a>) -> Ancestor<'a> {
+        Ancestor {
+            node,
+            parent: self.link,
+        }
+    }
+
+    /// Create the context for emitting the children of a node
+    #[inline]
+    pub(crate) fn child<'b>(&self, link: &'b Ancestor<'b>) -> RenderContext<'b>
+    where
+        'a: 'b,
+    {
+        RenderContext {
+            link: Some(link),
+            ..*self
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+
+    use indoc::indoc;
+
+    use crate::{cblock, cconcat, clist, Block, FormatCode};
+
+    use super::*;
+
+    #[test]
+    fn depth() {
+        fn top_level(_: &Block, ctx: &RenderContext<'_>) -> bool {
+            ctx.depth() == 0
+        }
+        let code = cblock!(
+            "{",
+            [cblock!(
+                "{",
+                [cblock!("{", ["a"], "}").inline_when_in(top_level)],
+                "}"
+            )
+            .inline_when_in(top_level)],
+            "}"
+        )
+        .never_inlined();
+        let expected = indoc! {"
+            {
+                {
+                    {
+                        a
+                    }
+                }
+            }"};
+        assert_eq!(expected, code.to_string());
+        let code = code.inline_when_in(top_level);
+        assert_eq!("{ { { a } } }", code.to_string());
+    }
+
+    #[test]
+    fn indent() {
+        fn shallow(_: &Block, ctx: &RenderContext<'_>) -> bool {
+            ctx.indent().len() < 8
+        }
+        let code = cblock!(
+            "{",
+            [
+                cblock!("{", [cblock!("{", ["a"], "}").inline_when_in(shallow)], "}")
+                    .never_inlined()
+            ],
+            "}"
+        )
+        .never_inlined();
+        let expected = indoc! {"
+            {
+                {
+                    {
+                        a
+                    }
+                }
+            }"};
+        assert_eq!(expected, code.to_string());
+        let expected = indoc! {"
+            {
+              {
+                { a }
+              }
+            }"};
+        assert_eq!(expected, code.format_with(&Format::indent(2)));
+        let expected = "{\n\t{\n\t\t{ a }\n\t}\n}";
+        assert_eq!(expected, code.format_with(&Format::indent_tab()));
+    }
+
+    #[test]
+    fn column() {
+        fn fits(list: &List, ctx: &RenderContext<'_>) -> bool {
+            ctx.column() + list.flat_width(ctx.format()) <= 20
+        }
+        let code = cconcat![
+            cblock!(
+                "foo(",
+                [clist!("," => ["aaaa", "bbbb"]).inline_when_in(fits)],
+                ");"
+            )
+            .inlined(),
+            cblock!(
+                "foo_bar_baz(",
+                [clist!("," => ["aaaa", "bbbb"]).inline_when_in(fits)],
+                ");"
+            ),
+        ];
+        let expected = indoc! {"
+            foo( aaaa, bbbb );
+            foo_bar_baz(
+                aaaa, bbbb
+            );"};
+        assert_eq!(expected, code.to_string());
+    }
+
+    #[test]
+    fn ancestors() {
+        fn in_call(list: &List, ctx: &RenderContext<'_>) -> bool {
+            let _ = list;
+            matches!(ctx.parent(), Some(NodeRef::Block(b)) if b.start.ends_with('('))
+                && ctx.ancestors().count() == 2
+        }
+        let code = cconcat![
+            cblock!(
+                "foo(",
+                [clist!("," => ["a", "b"]).inline_when_in(in_call)],
+                ")"
+            ),
+            cblock!(
+                "[",
+                [clist!("," => ["a", "b"]).inline_when_in(in_call)],
+                "]"
+            ),
+        ];
+        let expected = indoc! {"
+            foo( a, b )
+            [
+                a,
+                b,
+            ]"};
+        assert_eq!(expected, code.to_string());
+    }
+}
@@ -0,0 +1,221 @@
+use alloc::boxed::Box;
+use core::any::Any;
+use core::fmt::Debug;
+use core::hash::{Hash, Hasher};
+
+use crate::{Code, FormatCode, RenderContext};
+
+/// User-defined code structure, stored in [`Code::Custom`]
+///
+/// Implement [`FormatCode`] for emitting through the [`Emitter`](crate::Emitter),
+/// and this trait for layout information.
+/// Cloning, equality and hashing are provided by [`DynCode`], which is implemented automatically
+/// for types that are `Clone + PartialEq + Eq + Hash + Debug + Send + Sync`.
+///
+/// # Examples
+/// ```
+/// use codize::{cblock, clist, Code, CustomCode, Emitter, FormatCode, Format, RenderContext};
+///
+/// /// A SQL `CASE` expression, inlined if there is only one branch
+/// #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// struct Case(Vec<(String, String)>);
+///
+/// impl Case {
+///     fn to_code(&self) -> Code {
+///         let branches = self.0.iter().map(|(w, t)| format!("WHEN {w} THEN {t}"));
+///         let block = cblock!("CASE", branches, "END");
+///         if self.0.len() == 1 { block.inlined() } else { block }.into()
+///     }
+/// }
+///
+/// impl FormatCode for Case {
+///     fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+///         self.to_code().emit(ctx, emitter)
+///     }
+///     fn size_hint(&self) -> usize {
+///         self.0.len() + 2
+///     }
+///     fn flat_width(&self, format: &Format) -> usize {
+///         self.to_code().flat_width(format)
+///     }
+/// }
+///
+/// impl CustomCode for Case {
+///     fn should_inline_in(&self, _: &RenderContext<'_>) -> bool {
+///         self.0.len() == 1
+///     }
+///     fn is_empty(&self) -> bool {
+///         self.0.is_empty()
+///     }
+/// }
+///
+/// let code = cblock!("SELECT", [
+///     clist!("," => [
+///         Code::from("id"),
+///         Case(vec![("x > 0".into(), "1".into())]),
+///         Case(vec![("x > 0".into(), "1".into()), ("x < 0".into(), "-1".into())]),
+///     ])
+/// ], "FROM t;");
+///
+/// let expected =
+/// "SELECT
+///     id,
+///     CASE WHEN x > 0 THEN 1 END,
+///     CASE
+///         WHEN x > 0 THEN 1
+///         WHEN x < 0 THEN -1
+///     END,
+/// FROM t;";
+/// assert_eq!(expected, code.to_string());
+/// ```
+pub trait CustomCode: DynCode + FormatCode {
+    /// Should the code be displayed in one line, when emitted in the context.
+    /// This is used by the inline policies of the parents
+    fn should_inline_in(&self, ctx: &RenderContext<'_>) -> bool {
+        let _ = ctx;
+        false
+    }
+
+    /// Get if this structure will generate any code or not (empty = no code)
+    fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Get if the code contains a hard break. See [`Code::HardLine`]
+    fn has_hard_break(&self) -> bool {
+        false
+    }
+
+    /// Get if the last line emitted by the code is a hard break. See [`Code::HardLine`]
+    fn ends_with_hard_break(&self) -> bool {
+        false
+    }
+}
+
+/// Object-safe cloning, equality and hashing for [`CustomCode`]
+///
+/// This is implemented automatically
+pub trait DynCode: Debug + Send + Sync + 'static {
+    /// Clone into a box
+    fn clone_box(&self) -> Box<dyn CustomCode>;
+    /// Compare with another custom code
+    fn eq_dyn(&self, other: &dyn CustomCode) -> bool;
+    /// Hash into the hasher
+    fn hash_dyn(&self, state: &mut dyn Hasher);
+    /// Get self as [`Any`] for downcasting
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T> DynCode for T
+where
+    T: CustomCode + Clone + PartialEq + Eq + Hash + Debug + Send + Sync + 'static,
+{
+    fn clone_box(&self) -> Box<dyn CustomCode> {
+        Box::new(self.clone())
+    }
+
+    fn eq_dyn(&self, other: &dyn CustomCode) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+
+    fn hash_dyn(&self, mut state: &mut dyn Hasher) {
+        core::any::TypeId::of::<T>().hash(&mut state);
+        self.hash(&mut state);
+    }
+
+    #[inline]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Clone for Box<dyn CustomCode> {
+    #[inline]
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl PartialEq for dyn CustomCode {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_dyn(other)
+    }
+}
+
+impl Eq for dyn CustomCode {}
+
+impl Hash for dyn CustomCode {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash_dyn(state)
+    }
+}
+
+impl<T: CustomCode> From<T> for Code {
+    #[inline]
+    fn from(x: T) -> Self {
+        Code::Custom(Box::new(x))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::{String, ToString};
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::{cblock, Emitter, Format};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Verbatim(Vec<String>);
+
+    impl FormatCode for Verbatim {
+        fn emit(&self, _: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+            for line in &self.0 {
+                emitter.begin_line();
+                emitter.push_text(line);
+            }
+            emitter.hard_break();
+        }
+        fn size_hint(&self) -> usize {
+            self.0.len()
+        }
+        fn byte_size_hint(&self, _: &Format) -> usize {
+            self.0.iter().map(|l| l.len() + 1).sum()
+        }
+        fn flat_width(&self, format: &Format) -> usize {
+            self.0.iter().map(|l| format.width(l)).sum()
+        }
+    }
+
+    impl CustomCode for Verbatim {
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+        fn has_hard_break(&self) -> bool {
+            true
+        }
+        fn ends_with_hard_break(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn custom() {
+        let verbatim = Verbatim(vec!["#if X".to_string(), "#endif".to_string()]);
+        let code: Code = cblock!("{", ["a", verbatim.clone()], "}").inlined().into();
+        assert_eq!("{\n    a\n    #if X\n    #endif\n}", code.to_string());
+        assert!(code.has_hard_break());
+
+        let cloned = code.clone();
+        assert_eq!(code, cloned);
+        assert_ne!(
+            code,
+            cblock!("{", ["a", Verbatim(vec![])], "}").inlined().into()
+        );
+        assert_ne!(Code::from(verbatim), Code::from("#if X"));
+        assert!(Code::from(Verbatim(vec![])).is_empty());
+    }
+}
@@ -0,0 +1,600 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::fmt;
+
+use crate::{Connect, Format, Insertion};
+
+/// Destination for the lines produced by an [`Emitter`]
+pub trait Sink {
+    /// Write a finished line, without the line break. Lines are written in order.
+    ///
+    /// The sink can take the content of `line`. The emitter clears it afterward
+    fn write_line(&mut self, line: &mut String) -> fmt::Result;
+}
+
+impl Sink for Vec<String> {
+    #[inline]
+    fn write_line(&mut self, line: &mut String) -> fmt::Result {
+        self.push(core::mem::take(line));
+        Ok(())
+    }
+}
+
+/// Lines stored in one string, so the lines can contain line breaks
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub(crate) struct LineBuffer {
+    text: String,
+    /// End of each line in the text
+    ends: Vec<usize>,
+}
+
+#[cfg(feature = "std")]
+impl LineBuffer {
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &str> {
+        let starts = core::iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(&self.ends)
+            .map(|(start, end)| &self.text[start..*end])
+    }
+}
+
+#[cfg(feature = "std")]
+impl Sink for LineBuffer {
+    fn write_line(&mut self, line: &mut String) -> fmt::Result {
+        self.text.push_str(line);
+        self.ends.push(self.text.len());
+        Ok(())
+    }
+}
+
+/// What an emitter has not written when it stops. See [`Emitter::into_tail`]
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub(crate) struct Tail {
+    blank_lines: usize,
+    line: Option<String>,
+    /// If the line is ended by a hard break
+    line_ended: bool,
+    /// Number of lines started
+    line_count: usize,
+}
+
+/// Sink that writes the lines separated by `\n` to a [`fmt::Write`], such as a [`String`]
+#[derive(Debug)]
+pub struct TextSink<W> {
+    out: W,
+    first: bool,
+}
+
+impl<W: fmt::Write> TextSink<W> {
+    /// Create a sink that writes to the output
+    #[inline]
+    pub fn new(out: W) -> Self {
+        Self { out, first: true }
+    }
+
+    /// Get the output back
+    #[inline]
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: fmt::Write> Sink for TextSink<W> {
+    fn write_line(&mut self, line: &mut String) -> fmt::Result {
+        if self.first {
+            self.first = false;
+        } else {
+            self.out.write_char('\n')?;
+        }
+        self.out.write_str(line)
+    }
+}
+
+/// Builds lines of code and writes them to a [`Sink`]
+///
+/// Code structures emit themselves through [`FormatCode::emit`](crate::FormatCode::emit)
+/// by calling [`connect`](Self::connect) or [`begin_line`](Self::begin_line)
+/// to decide where the next text goes, then [`push_text`](Self::push_text).
+/// The emitter takes care of:
+/// - indentation of new lines
+/// - joining connected text with spaces (or not, when minifying)
+/// - hard breaks (nothing can be connected after [`hard_break`](Self::hard_break))
+/// - removing whitespaces from blank lines, and collapsing and trimming blank lines
+///   according to the [`Format`]
+pub struct Emitter<'a> {
+    format: &'a Format,
+    sink: &'a mut dyn Sink,
+    state: EmitterState,
+}
+
+/// State of an [`Emitter`] without the sink, so emitting can be paused and continued
+pub(crate) struct EmitterState {
+    result: fmt::Result,
+    /// One level of indentation
+    indent_unit: String,
+    /// Indentation for new lines
+    indent: String,
+    /// Display width of the indentation for new lines
+    indent_width: usize,
+    /// The current line, including the indentation
+    line: String,
+    /// Length and display width of the start of the current line that is measured,
+    /// so getting the column does not measure the whole line again
+    measured: Cell<(usize, usize)>,
+    /// If there is a current line
+    has_line: bool,
+    /// If the current line has non-whitespace content
+    has_content: bool,
+    /// If the current line is ended by a hard break
+    line_ended: bool,
+    /// How the next text is joined to the current line
+    pending: Pending,
+    /// Length of the current line before the joiner text, to undo the joiner
+    joiner_start: usize,
+    /// Number of blank lines before the current line that are not written yet
+    blank_lines: usize,
+    /// If no content is emitted since the last indent
+    at_block_start: bool,
+    /// Number of lines started
+    line_count: usize,
+    /// Column where the first line starts, when inserting in the middle of a line
+    start_column: Option<usize>,
+}
+
+/// How the next text is joined to the current line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pending {
+    /// Start a new line
+    NewLine,
+    /// Join with a space if needed
+    Space,
+    /// Join directly (the joiner text, if any, is already pushed)
+    Tight,
+}
+
+impl<'a> Emitter<'a> {
+    /// Create an emitter that writes to the sink with the format
+    pub fn new(format: &'a Format, sink: &'a mut dyn Sink) -> Self {
+        let indent_unit = match format.indent {
+            i if i < 0 => "\t".to_string(),
+            i => " ".repeat(i as usize),
+        };
+        let state = EmitterState {
+            result: Ok(()),
+            indent_unit,
+            indent: String::new(),
+            indent_width: 0,
+            line: String::new(),
+            measured: Cell::new((0, 0)),
+            has_line: false,
+            has_content: false,
+            line_ended: false,
+            pending: Pending::NewLine,
+            joiner_start: 0,
+            blank_lines: 0,
+            at_block_start: false,
+            line_count: 0,
+            start_column: None,
+        };
+        Self {
+            format,
+            sink,
+            state,
+        }
+    }
+
+    /// Create an emitter for code inserted into existing text. See [`Insertion`]
+    pub fn new_at(format: &'a Format, sink: &'a mut dyn Sink, insertion: &Insertion) -> Self {
+        let mut emitter = Self::new(format, sink);
+        emitter.set_base_indent(&insertion.indent_text(format));
+        emitter.state.start_column = insertion.column();
+        emitter
+    }
+
+    /// Continue emitting to the sink from a paused state, with the same format
+    #[cfg(feature = "std")]
+    pub(crate) fn resume(format: &'a Format, sink: &'a mut dyn Sink, state: EmitterState) -> Self {
+        Self {
+            format,
+            sink,
+            state,
+        }
+    }
+
+    /// Pause emitting and take the state. Everything not written to the sink is kept
+    #[cfg(feature = "std")]
+    pub(crate) fn pause(self) -> EmitterState {
+        self.state
+    }
+
+    /// Get the format being used
+    #[inline]
+    pub fn format(&self) -> &'a Format {
+        self.format
+    }
+
+    /// Make the next text start on a new line.
+    ///
+    /// The line is only created when text is pushed, so calling this multiple times
+    /// does not create blank lines. Push an empty text to create a blank line
+    #[inline]
+    pub fn begin_line(&mut self) {
+        self.connect(&Connect::NewLine);
+    }
+
+    /// Set how the next text is joined to the current line. This overrides the previous call.
+    ///
+    /// [`Connect::Auto`] and [`Connect::NewLine`] make the next text start on a new line.
+    /// If the current line is ended by a hard break, the next text always starts on a new line
+    pub fn connect(&mut self, mode: &Connect) {
+        // undo the joiner of the previous call
+        if self.state.has_line && self.state.pending == Pending::Tight {
+            self.state.line.truncate(self.state.joiner_start);
+            if self.state.measured.get().0 > self.state.joiner_start {
+                self.state.measured.set((0, 0));
+            }
+        }
+        self.state.pending = match mode {
+            Connect::Auto | Connect::NewLine => Pending::NewLine,
+            Connect::Space => Pending::Space,
+            Connect::Tight => Pending::Tight,
+            Connect::Join(joiner) => {
+                if self.state.has_line && !self.state.line_ended {
+                    self.state.line.push_str(joiner);
+                }
+                Pending::Tight
+            }
+        };
+    }
+
+    /// Push text to the current line, joined according to the last [`connect`](Self::connect)
+    ///
+    /// The text is pushed as-is. Following text is joined directly unless
+    /// `connect` or `begin_line` is called
+    pub fn push_text(&mut self, text: &str) {
+        if !self.state.has_line || self.state.line_ended || self.state.pending == Pending::NewLine {
+            self.new_line();
+        } else if self.state.pending == Pending::Space && self.needs_space(text) {
+            self.state.line.push(' ');
+        }
+        self.state.line.push_str(text);
+        self.state.pending = Pending::Tight;
+        self.state.joiner_start = self.state.line.len();
+        if !self.state.has_content && !crate::is_blank(text) {
+            self.state.has_content = true;
+            self.flush_blank_lines();
+        }
+    }
+
+    /// Push the text of a [`Code::HardLine`](crate::Code::HardLine) and end its line.
+    ///
+    /// When minifying, the text also starts on a new line, since it can be a line comment
+    /// or a preprocessor directive. Otherwise it is joined like [`push_text`](Self::push_text)
+    pub fn push_hard_line(&mut self, text: &str) {
+        if self.format.minify {
+            self.begin_line();
+        }
+        self.push_text(text);
+        self.hard_break();
+    }
+
+    /// End the current line with a hard break. Nothing can be connected to it afterward.
+    /// See [`Code::HardLine`](crate::Code::HardLine)
+    #[inline]
+    pub fn hard_break(&mut self) {
+        self.state.line_ended = true;
+    }
+
+    /// Increase the indentation for new lines
+    pub fn indent(&mut self) {
+        self.state.indent.push_str(&self.state.indent_unit);
+        self.state.indent_width += self.format.indent_width();
+        self.state.at_block_start = true;
+    }
+
+    /// Decrease the indentation for new lines
+    ///
+    /// If [`Format::trim_blank_lines`] is set, blank lines at the end of the indented section
+    /// are removed
+    pub fn dedent(&mut self) {
+        if self.format.trim_blank_lines {
+            self.state.blank_lines = 0;
+            if self.state.has_line && !self.state.has_content {
+                self.state.has_line = false;
+            }
+        }
+        let len = self
+            .state
+            .indent
+            .len()
+            .saturating_sub(self.state.indent_unit.len());
+        self.state.indent.truncate(len);
+        self.state.indent_width = self
+            .state
+            .indent_width
+            .saturating_sub(self.format.indent_width());
+        self.state.at_block_start = false;
+    }
+
+    /// Get the indentation for new lines
+    #[inline]
+    pub(crate) fn indent_text(&self) -> &str {
+        &self.state.indent
+    }
+
+    /// Get the number of lines started so far
+    #[inline]
+    pub fn line_count(&self) -> usize {
+        self.state.line_count
+    }
+
+    /// Get the column (display width from the start of the line) where the next text will start
+    pub fn column(&self) -> usize {
+        let start = match self.state.start_column {
+            Some(column) if self.state.line_count <= 1 => column,
+            _ => 0,
+        };
+        if self.at_new_line() {
+            if self.state.line_count == 0 && self.state.start_column.is_some() {
+                return start;
+            }
+            return self.state.indent_width;
+        }
+        let (measured, width) = self.state.measured.get();
+        let width = width + self.format.width(&self.state.line[measured..]);
+        self.state.measured.set((self.state.line.len(), width));
+        let width = start + width;
+        if self.state.pending == Pending::Space && self.state.has_content && !self.format.minify {
+            width + 1
+        } else {
+            width
+        }
+    }
+
+    /// Get if the next text starts on a new line
+    pub(crate) fn at_new_line(&self) -> bool {
+        !self.state.has_line || self.state.line_ended || self.state.pending == Pending::NewLine
+    }
+
+    /// Stop emitting without writing the blank lines and the current line,
+    /// so they can be replayed to another emitter
+    #[cfg(feature = "std")]
+    pub(crate) fn into_tail(mut self) -> Tail {
+        let line = self
+            .state
+            .has_line
+            .then(|| core::mem::take(&mut self.state.line));
+        Tail {
+            blank_lines: self.state.blank_lines,
+            line,
+            line_ended: self.state.line_ended,
+            line_count: self.state.line_count,
+        }
+    }
+
+    /// Push the lines emitted separately by an emitter without indentation,
+    /// as if they were emitted here
+    #[cfg(feature = "std")]
+    pub(crate) fn replay(&mut self, lines: &LineBuffer, tail: &Tail) {
+        let start = self.state.line_count;
+        let blank_lines = core::iter::repeat_n("", tail.blank_lines);
+        for line in lines.iter().chain(blank_lines).chain(tail.line.as_deref()) {
+            self.begin_line();
+            self.push_text(line);
+        }
+        if tail.line.is_some() && tail.line_ended {
+            self.hard_break();
+        }
+        self.state.line_count = self.state.line_count.max(start + tail.line_count);
+    }
+
+    /// Get if nothing is emitted yet and the indentation is not changed
+    #[cfg(feature = "rayon")]
+    pub(crate) fn is_at_start(&self) -> bool {
+        !self.state.has_line
+            && self.state.line_count == 0
+            && self.state.blank_lines == 0
+            && self.state.indent.is_empty()
+            && self.state.start_column.is_none()
+    }
+
+    /// Write the remaining lines to the sink
+    pub fn finish(mut self) -> fmt::Result {
+        self.finish_line();
+        self.flush_blank_lines();
+        self.state.result
+    }
+
+    /// Add text before the indentation of all new lines
+    fn set_base_indent(&mut self, indent: &str) {
+        let tabs = indent.matches('\t').count();
+        let width = tabs * self.format.tab_width + self.format.width(&indent.replace('\t', ""));
+        self.state.indent.insert_str(0, indent);
+        self.state.indent_width += width;
+    }
+
+    fn needs_space(&self, text: &str) -> bool {
+        if !self.state.has_content {
+            return false;
+        }
+        if self.format.minify {
+            crate::requires_space(&self.state.line, text)
+        } else {
+            true
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.finish_line();
+        self.state.line.clear();
+        self.state.measured.set((0, 0));
+        if self.state.line_count > 0 || self.state.start_column.is_none() {
+            self.state.line.push_str(&self.state.indent);
+        }
+        self.state.has_line = true;
+        self.state.has_content = false;
+        self.state.line_ended = false;
+        self.state.line_count += 1;
+    }
+
+    fn finish_line(&mut self) {
+        if !self.state.has_line {
+            return;
+        }
+        self.state.has_line = false;
+        if !self.state.has_content {
+            if !(self.format.trim_blank_lines && self.state.at_block_start) {
+                self.state.blank_lines += 1;
+            }
+            return;
+        }
+        self.write_line();
+    }
+
+    fn flush_blank_lines(&mut self) {
+        let mut count = core::mem::take(&mut self.state.blank_lines);
+        if self.format.trim_blank_lines && self.state.at_block_start {
+            count = 0;
+        }
+        if let Some(max) = self.format.max_blank_lines {
+            count = count.min(max);
+        }
+        for _ in 0..count {
+            self.write_blank_line();
+        }
+        self.state.at_block_start = false;
+    }
+
+    fn write_blank_line(&mut self) {
+        if self.state.result.is_ok() {
+            self.state.result = self.sink.write_line(&mut String::new());
+        }
+    }
+
+    fn write_line(&mut self) {
+        if self.state.result.is_ok() {
+            self.state.result = self.sink.write_line(&mut self.state.line);
+        }
+        self.state.line.clear();
+        self.state.measured.set((0, 0));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+
+    use super::*;
+
+    fn emit_with(format: &Format, f: impl FnOnce(&mut Emitter<'_>)) -> Vec<String> {
+        let mut out = Vec::new();
+        let mut emitter = Emitter::new(format, &mut out);
+        f(&mut emitter);
+        emitter.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn text_sink() {
+        let format = Format::default();
+        let mut sink = TextSink::new(String::new());
+        let mut emitter = Emitter::new(&format, &mut sink);
+        for line in ["", "a", "", ""] {
+            emitter.begin_line();
+            emitter.push_text(line);
+        }
+        emitter.finish().unwrap();
+        assert_eq!("\na\n\n", sink.into_inner());
+    }
+
+    #[test]
+    fn connect() {
+        let out = emit_with(&Format::default(), |e| {
+            e.push_text("a");
+            e.connect(&Connect::Space);
+            e.push_text("b");
+            e.connect(&Connect::Join(", ".into()));
+            e.connect(&Connect::Tight);
+            e.push_text("c");
+            e.connect(&Connect::Join("; ".into()));
+            e.push_text("d");
+            e.connect(&Connect::Join(";".into()));
+            e.begin_line();
+            e.push_text("e");
+        });
+        assert_eq!(vec!["a bc; d", "e"], out);
+    }
+
+    #[test]
+    fn hard_break() {
+        let out = emit_with(&Format::default(), |e| {
+            e.push_text("// a");
+            e.hard_break();
+            e.connect(&Connect::Join(",".into()));
+            e.push_text("b");
+            e.connect(&Connect::Space);
+            e.push_text("c");
+        });
+        assert_eq!(vec!["// a", "b c"], out);
+    }
+
+    #[test]
+    fn indent() {
+        let format = Format::indent(2);
+        let out = emit_with(&format, |e| {
+            e.push_text("{");
+            e.indent();
+            assert_eq!(1, e.column());
+            e.begin_line();
+            assert_eq!(2, e.column());
+            e.push_text("a");
+            e.connect(&Connect::Space);
+            assert_eq!(4, e.column());
+            e.dedent();
+            e.begin_line();
+            e.push_text("}");
+        });
+        assert_eq!(vec!["{", "  a", "}"], out);
+    }
+
+    #[test]
+    fn blank_lines() {
+        let body = |e: &mut Emitter<'_>| {
+            e.push_text("{");
+            e.indent();
+            for line in ["", "", "a", "", "", "", "b", " ", ""] {
+                e.begin_line();
+                e.push_text(line);
+            }
+            e.dedent();
+            e.begin_line();
+            e.push_text("}");
+            e.begin_line();
+            e.push_text("");
+        };
+        let out = emit_with(&Format::default(), body);
+        assert_eq!(
+            vec!["{", "", "", "    a", "", "", "", "    b", "", "", "}", ""],
+            out
+        );
+        let format = Format::default()
+            .set_max_blank_lines(1)
+            .set_trim_blank_lines(true);
+        let out = emit_with(&format, body);
+        assert_eq!(vec!["{", "    a", "", "    b", "}", ""], out);
+    }
+
+    #[test]
+    fn minify() {
+        let out = emit_with(&Format::minify(), |e| {
+            for text in ["let", "x", "=", "-", "-1", ";"] {
+                e.connect(&Connect::Space);
+                e.push_text(text);
+            }
+        });
+        assert_eq!(vec!["let x= - -1;"], out);
+    }
+}
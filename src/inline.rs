@@ -0,0 +1,577 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+
+use crate::tree::{Node, Tree};
+use crate::width::FlatWidth;
+use crate::{Format, NodeRef, RenderContext, Trailing};
+
+/// A custom inline condition. See [`InlinePolicy::Custom`]
+pub type InlineFn<T> = Arc<dyn Fn(&T, &RenderContext<'_>) -> bool + Send + Sync>;
+
+/// When to display a [`Block`](crate::Block) or [`List`](crate::List) in one line
+///
+/// Regardless of the policy, blocks and lists containing hard breaks are never inlined.
+/// See [`Code::HardLine`](crate::Code::HardLine)
+#[derive(derivative::Derivative)]
+#[derivative(Debug(bound = ""), Clone(bound = ""), Default(bound = ""))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound = ""))]
+pub enum InlinePolicy<T> {
+    /// Always inline
+    Always,
+    /// Never inline
+    Never,
+    /// Inline if there is only one item, and that item should be inlined
+    #[derivative(Default)]
+    Intrinsic,
+    /// Inline if there are at most this many items
+    MaxItems(usize),
+    /// Inline if the display width in one line is at most this many columns.
+    /// See [`FormatCode::flat_width`](crate::FormatCode::flat_width)
+    MaxWidth(usize),
+    /// Inline if every item is displayed in one line. Lines are always displayed in one line
+    AllChildrenInline,
+    /// Inline if the function returns true
+    ///
+    /// The function can capture state, such as configuration loaded at runtime.
+    /// Custom policies are compared and hashed by the address of the function,
+    /// and cannot be serialized
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(#[derivative(Debug = "ignore")] InlineFn<T>),
+}
+
+impl<T> InlinePolicy<T> {
+    /// Create a [`InlinePolicy::Custom`] policy from a function
+    pub fn custom<F>(condition: F) -> Self
+    where
+        F: Fn(&T, &RenderContext<'_>) -> bool + Send + Sync + 'static,
+    {
+        Self::Custom(Arc::new(condition))
+    }
+}
+
+impl<T> PartialEq for InlinePolicy<T> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::MaxItems(a), Self::MaxItems(b)) => a == b,
+            (Self::MaxWidth(a), Self::MaxWidth(b)) => a == b,
+            (Self::Custom(a), Self::Custom(b)) => {
+                core::ptr::addr_eq(Arc::as_ptr(a), Arc::as_ptr(b))
+            }
+            (a, b) => core::mem::discriminant(a) == core::mem::discriminant(b),
+        }
+    }
+}
+
+impl<T> Eq for InlinePolicy<T> {}
+
+impl<T> Hash for InlinePolicy<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
+        match self {
+            Self::MaxItems(n) | Self::MaxWidth(n) => n.hash(state),
+            Self::Custom(f) => Arc::as_ptr(f).cast::<()>().hash(state),
+            _ => {}
+        }
+    }
+}
+
+/// Code structures with an [`InlinePolicy`]
+pub(crate) trait InlineTarget {
+    /// Reference to self as an ancestor of the items
+    fn node(&self) -> NodeRef<'_>;
+    /// The width before the first item when inlined
+    fn inline_offset(&self, format: &Format) -> usize;
+}
+
+/// Inline decision of a node, made without the render context
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    Inline,
+    Break,
+    /// The decision depends on the render context, because of custom conditions or custom code
+    InContext,
+}
+
+impl Decision {
+    #[inline]
+    fn from_bool(inline: bool) -> Self {
+        if inline {
+            Self::Inline
+        } else {
+            Self::Break
+        }
+    }
+}
+
+/// What is known about a node without the render context
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Summary {
+    /// See [`Code::is_empty`](crate::Code::is_empty)
+    empty: bool,
+    /// See [`Code::has_hard_break`](crate::Code::has_hard_break)
+    hard_break: bool,
+    /// See [`FormatCode::flat_width`](crate::FormatCode::flat_width)
+    pub(crate) width: usize,
+    /// Should the node be displayed in one line. See [`should_inline`]
+    inline: Decision,
+    /// Is the node displayed in one line when emitted by itself
+    single_line: Decision,
+}
+
+/// Summaries of the nodes in a tree, computed bottom-up once for each node
+///
+/// The summaries are kept for a render, so deciding to inline each block does not visit
+/// its body again
+#[derive(Debug, Default)]
+pub(crate) struct Summaries {
+    /// Summaries of the blocks, lists, concats, connected and custom codes, by address
+    nodes: BTreeMap<usize, Summary>,
+}
+
+impl Summaries {
+    /// Get the summary of the node, summarizing the nodes below it that are not summarized yet
+    pub(crate) fn get<'a, T: Tree<'a>>(&mut self, tree: T, id: T::Id, format: &Format) -> Summary {
+        if let Some(summary) = self.lookup(tree, id, format) {
+            return summary;
+        }
+        // the children are summarized before the parent, without recursion
+        let mut stack = vec![(id, false)];
+        while let Some((id, visited)) = stack.pop() {
+            if visited {
+                let summary = self.summarize(tree, id, format);
+                if let Some(key) = key(tree.node(id)) {
+                    self.nodes.insert(key, summary);
+                }
+            } else if self.lookup(tree, id, format).is_none() {
+                stack.push((id, true));
+                stack.extend(tree.children(id).map(|c| (c, false)));
+            }
+        }
+        self.lookup(tree, id, format).expect("node is summarized")
+    }
+
+    fn lookup<'a, T: Tree<'a>>(&self, tree: T, id: T::Id, format: &Format) -> Option<Summary> {
+        match tree.node(id) {
+            Node::Line(line) => Some(Summary {
+                empty: false,
+                hard_break: false,
+                width: format.width(line),
+                inline: Decision::Break,
+                single_line: Decision::Inline,
+            }),
+            Node::HardLine(line) => Some(Summary {
+                empty: false,
+                hard_break: true,
+                width: format.width(line),
+                inline: Decision::Break,
+                single_line: Decision::Break,
+            }),
+            node => self.nodes.get(&key(node)?).copied(),
+        }
+    }
+
+    /// Summarize the node, after the children are summarized
+    fn summarize<'a, T: Tree<'a>>(&self, tree: T, id: T::Id, format: &Format) -> Summary {
+        let children: Vec<Summary> = tree
+            .children(id)
+            .map(|c| self.lookup(tree, c, format).expect("child is summarized"))
+            .collect();
+        let hard_break = children.iter().any(|c| c.hard_break);
+        let mut width = FlatWidth::default();
+        match tree.node(id) {
+            Node::Block(block) => {
+                width.join(format.width(&block.start));
+                for c in &children {
+                    width.join(c.width);
+                }
+                if !block.no_end {
+                    width.join(format.width(&block.end));
+                }
+                let inline = decide(&block.inline_policy, &children, width.get(), hard_break);
+                Summary {
+                    empty: false,
+                    hard_break,
+                    width: width.get(),
+                    inline,
+                    single_line: inline,
+                }
+            }
+            Node::List(list) => {
+                let separator = format.width(&list.separator);
+                let mut first = true;
+                for c in children.iter().filter(|c| !c.empty) {
+                    if !first {
+                        width.push(separator);
+                    }
+                    width.join(c.width);
+                    first = false;
+                }
+                if !first && list.trailing == Trailing::Always {
+                    width.push(separator);
+                }
+                let inline = decide(&list.inline_policy, &children, width.get(), hard_break);
+                Summary {
+                    empty: children.is_empty(),
+                    hard_break,
+                    width: width.get(),
+                    inline,
+                    single_line: inline,
+                }
+            }
+            Node::Concat(_) => {
+                for c in &children {
+                    width.join(c.width);
+                }
+                let mut sections = children.iter().filter(|c| !c.empty);
+                let single_line = match (sections.next(), sections.next()) {
+                    (None, _) => Decision::Inline,
+                    (Some(section), None) => section.single_line,
+                    _ => Decision::Break,
+                };
+                Summary {
+                    empty: children.is_empty(),
+                    hard_break,
+                    width: width.get(),
+                    inline: Decision::Break,
+                    single_line,
+                }
+            }
+            Node::Connected(_) => children[0],
+            Node::Custom(body) => Summary {
+                empty: body.is_empty(),
+                hard_break: body.has_hard_break(),
+                width: body.flat_width(format),
+                inline: Decision::InContext,
+                single_line: Decision::InContext,
+            },
+            Node::Line(_) | Node::HardLine(_) => {
+                self.lookup(tree, id, format).expect("lines are summarized")
+            }
+        }
+    }
+}
+
+/// Key of the node in [`Summaries`]. Lines are cheap to summarize, and are not kept
+fn key(node: Node<'_>) -> Option<usize> {
+    let address = match node {
+        Node::Block(block) => core::ptr::from_ref(block).addr(),
+        Node::List(list) => core::ptr::from_ref(list).addr(),
+        Node::Concat(concat) => core::ptr::from_ref(concat).addr(),
+        Node::Connected(mode) => core::ptr::from_ref(mode).addr(),
+        Node::Custom(body) => core::ptr::from_ref(body).addr(),
+        Node::Line(_) | Node::HardLine(_) => return None,
+    };
+    Some(address)
+}
+
+/// Decide if the block or list with the summaries of the items should be inlined
+fn decide<P>(
+    policy: &InlinePolicy<P>,
+    items: &[Summary],
+    width: usize,
+    hard_break: bool,
+) -> Decision {
+    if hard_break {
+        return Decision::Break;
+    }
+    match policy {
+        InlinePolicy::Always => Decision::Inline,
+        InlinePolicy::Never => Decision::Break,
+        InlinePolicy::Intrinsic => match items {
+            [item] => item.inline,
+            _ => Decision::Break,
+        },
+        InlinePolicy::MaxItems(n) => {
+            Decision::from_bool(items.iter().filter(|c| !c.empty).count() <= *n)
+        }
+        InlinePolicy::MaxWidth(n) => Decision::from_bool(width <= *n),
+        InlinePolicy::AllChildrenInline => {
+            let mut decision = Decision::Inline;
+            for item in items {
+                match item.single_line {
+                    Decision::Inline => {}
+                    Decision::Break => return Decision::Break,
+                    Decision::InContext => decision = Decision::InContext,
+                }
+            }
+            decision
+        }
+        InlinePolicy::Custom(_) => Decision::InContext,
+    }
+}
+
+/// See [`Code::should_inline_in`](crate::Code::should_inline_in)
+///
+/// Only decisions depending on the context are evaluated with it,
+/// the others are taken from the summaries
+pub(crate) fn should_inline<'a: 'c, 'c, T: Tree<'a>>(
+    tree: T,
+    mut id: T::Id,
+    ctx: &RenderContext<'c>,
+    summaries: &mut Summaries,
+) -> bool {
+    match summaries.get(tree, id, ctx.format()).inline {
+        Decision::Inline => return true,
+        Decision::Break => return false,
+        Decision::InContext => {}
+    }
+    loop {
+        match tree.node(id) {
+            Node::Block(block) => {
+                return evaluate(tree, id, &block.inline_policy, block, ctx, summaries)
+            }
+            Node::List(list) => {
+                return evaluate(tree, id, &list.inline_policy, list, ctx, summaries)
+            }
+            Node::Connected(_) => id = tree.child(id, 0),
+            Node::Custom(body) => return body.should_inline_in(ctx),
+            _ => return false,
+        }
+    }
+}
+
+/// Evaluate the policy on the target, which is the node `id` in the tree, in the context.
+/// This does not check for hard breaks
+fn evaluate<'a: 'c, 'c, T: Tree<'a>, P: InlineTarget>(
+    tree: T,
+    id: T::Id,
+    policy: &InlinePolicy<P>,
+    target: &'a P,
+    ctx: &RenderContext<'c>,
+    summaries: &mut Summaries,
+) -> bool {
+    let format = ctx.format();
+    match policy {
+        InlinePolicy::Intrinsic => evaluate_intrinsic(tree, id, ctx, summaries),
+        InlinePolicy::AllChildrenInline => {
+            let link = ctx.link(target.node());
+            let column = ctx.column() + target.inline_offset(format);
+            let child_ctx = ctx.child(&link).with_column(column);
+            tree.children(id)
+                .all(|c| is_single_line(tree, c, &child_ctx, summaries))
+        }
+        InlinePolicy::Custom(f) => f(target, ctx),
+        // the other policies do not depend on the context
+        _ => summaries.get(tree, id, format).inline == Decision::Inline,
+    }
+}
+
+/// Evaluate [`InlinePolicy::Intrinsic`] on the node in the context
+///
+/// Chains of single items with the intrinsic policy are followed in a loop instead of recursion,
+/// since they can be deeply nested. This does not check for hard breaks
+fn evaluate_intrinsic<'a: 'c, 'c, T: Tree<'a>>(
+    tree: T,
+    mut id: T::Id,
+    ctx: &RenderContext<'c>,
+    summaries: &mut Summaries,
+) -> bool {
+    let format = ctx.format();
+    let mut column = ctx.column();
+    // the nodes followed so far, below the ancestors in the context
+    let mut path: Vec<NodeRef<'c>> = Vec::new();
+    loop {
+        let (node, offset) = match tree.node(id) {
+            Node::Block(block) => (NodeRef::Block(block), block.inline_offset(format)),
+            Node::List(list) => (NodeRef::List(list), list.inline_offset(format)),
+            _ => return false,
+        };
+        if tree.len(id) != 1 {
+            return false;
+        }
+        column += offset;
+        path.push(node);
+        let mut item = tree.child(id, 0);
+        while let Node::Connected(_) = tree.node(item) {
+            item = tree.child(item, 0);
+        }
+        match summaries.get(tree, item, format).inline {
+            Decision::Inline => return true,
+            Decision::Break => return false,
+            Decision::InContext => {}
+        }
+        match tree.node(item) {
+            Node::Block(block) if block.inline_policy == InlinePolicy::Intrinsic => {}
+            Node::List(list) if list.inline_policy == InlinePolicy::Intrinsic => {}
+            _ => {
+                // build the context of the item to evaluate other policies
+                let mut ancestors: Vec<_> = ctx.ancestors().collect();
+                ancestors.reverse();
+                ancestors.append(&mut path);
+                let ctx = RenderContext::with_ancestors(
+                    format,
+                    ctx.depth(),
+                    ctx.indent(),
+                    column,
+                    &ancestors,
+                );
+                return should_inline(tree, item, &ctx, summaries);
+            }
+        }
+        id = item;
+    }
+}
+
+/// Get if the code is displayed in one line when emitted by itself in the context
+fn is_single_line<'a: 'c, 'c, T: Tree<'a>>(
+    tree: T,
+    mut id: T::Id,
+    ctx: &RenderContext<'c>,
+    summaries: &mut Summaries,
+) -> bool {
+    let format = ctx.format();
+    loop {
+        match summaries.get(tree, id, format).single_line {
+            Decision::Inline => return true,
+            Decision::Break => return false,
+            Decision::InContext => {}
+        }
+        id = match tree.node(id) {
+            // the only section that is not empty
+            Node::Concat(_) => {
+                let mut sections = tree.children(id);
+                match sections.find(|c| !summaries.get(tree, *c, format).empty) {
+                    Some(section) => section,
+                    None => return true,
+                }
+            }
+            Node::Connected(_) => tree.child(id, 0),
+            _ => return should_inline(tree, id, ctx, summaries),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use std::collections::hash_map::DefaultHasher;
+
+    use indoc::indoc;
+
+    use super::*;
+    use crate::{cblock, clist, Block, FormatCode, List};
+
+    fn hash<T: Hash>(x: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        x.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn policies() {
+        let list = clist!("," => ["1", "2", "3"]);
+        let format = Format::default();
+        let expected = "[ 1, 2, 3 ]";
+        let code = cblock!(
+            "[",
+            [list.clone().inline_policy(InlinePolicy::MaxItems(3))],
+            "]"
+        );
+        assert_eq!(expected, code.to_string());
+        let code = cblock!(
+            "[",
+            [list.clone().inline_policy(InlinePolicy::MaxWidth(7))],
+            "]"
+        );
+        assert_eq!(expected, code.to_string());
+        let code =
+            cblock!("[", [list.clone().inlined()], "]").inline_policy(InlinePolicy::MaxWidth(11));
+        assert!(code.should_inline_in(&RenderContext::new(&format)));
+        let code = code.inline_policy(InlinePolicy::MaxWidth(10));
+        assert!(!code.should_inline_in(&RenderContext::new(&format)));
+
+        let expected = indoc! {"
+            [
+                1,
+                2,
+                3,
+            ]"};
+        let code = cblock!(
+            "[",
+            [list.clone().inline_policy(InlinePolicy::MaxItems(2))],
+            "]"
+        );
+        assert_eq!(expected, code.to_string());
+        let code = cblock!("[", [list.inline_policy(InlinePolicy::MaxWidth(6))], "]");
+        assert_eq!(expected, code.to_string());
+    }
+
+    #[test]
+    fn all_children_inline() {
+        let code = cblock!("{", ["a", cblock!("{", ["b"], "}").inlined()], "}")
+            .inline_policy(InlinePolicy::AllChildrenInline);
+        assert_eq!("{ a { b } }", code.to_string());
+        let code = cblock!("{", ["a", cblock!("{", ["b"], "}")], "}")
+            .inline_policy(InlinePolicy::AllChildrenInline);
+        assert!(!code.should_inline());
+    }
+
+    #[test]
+    fn equality() {
+        fn is_short(list: &List) -> bool {
+            list.body().len() < 3
+        }
+        let a = cblock!("{", ["a"], "}");
+        assert_ne!(a, a.clone().never_inlined());
+        assert_ne!(hash(&a), hash(&a.clone().never_inlined()));
+        assert_eq!(
+            a.clone().inlined(),
+            a.clone().inline_policy(InlinePolicy::Always)
+        );
+        assert_eq!(hash(&a.clone().inlined()), hash(&a.inlined()));
+        let b = clist!("," => ["a"]).inline_when(is_short);
+        assert_eq!(b, b.clone());
+        assert_ne!(b, clist!("," => ["a"]).inline_when(is_short));
+        assert_eq!(hash(&b), hash(&b.clone()));
+        assert_ne!(b, b.clone().inline_policy(InlinePolicy::MaxItems(2)));
+        assert_ne!(
+            InlinePolicy::<Block>::MaxItems(2),
+            InlinePolicy::<Block>::MaxWidth(2)
+        );
+    }
+
+    #[test]
+    fn capturing() {
+        fn assert_send_sync<T: Clone + Send + Sync>(_: &T) {}
+        let compact = alloc::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let code = cblock!("{", ["a", "b"], "}").inline_when({
+            let compact = compact.clone();
+            move |_| compact.load(std::sync::atomic::Ordering::Relaxed)
+        });
+        assert_send_sync(&code);
+        assert_eq!("{\n    a\n    b\n}", code.to_string());
+        compact.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!("{ a b }", code.to_string());
+
+        let threshold = 3;
+        let code = clist!("," => ["a", "b", "c"]).inline_when_in(move |list, ctx| {
+            ctx.format().indent < threshold && list.body().len() <= 3
+        });
+        assert_eq!("a,\nb,\nc,", code.to_string());
+        assert_eq!("a, b, c", code.format_with(&Format::indent(2)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        use crate::Code;
+
+        let code: Code = cblock!(
+            "{",
+            [clist!("," => ["a", "b"]).inline_policy(InlinePolicy::MaxWidth(80))],
+            "}"
+        )
+        .never_inlined()
+        .into();
+        let json = serde_json::to_string(&code).unwrap();
+        let parsed: Code = serde_json::from_str(&json).unwrap();
+        assert_eq!(code, parsed);
+
+        let code: Code = cblock!("{", ["a"], "}").inline_when(|_| true).into();
+        assert!(serde_json::to_string(&code).is_err());
+    }
+}
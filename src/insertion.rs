@@ -0,0 +1,160 @@
+use alloc::borrow::Cow;
+use alloc::string::ToString;
+
+use crate::Format;
+
+/// Where emitted code is inserted into existing text
+///
+/// Whole lines are inserted at a base indentation, given as a number of levels of the
+/// [`Format`] or as the indentation text. Use [`set_column`](Self::set_column) to insert
+/// in the middle of a line instead, where the first line continues the text before it
+/// and the following lines are at the base indentation.
+/// See [`FormatCode::format_at`](crate::FormatCode::format_at)
+///
+/// # Examples
+/// ```
+/// use codize::{cblock, Format, FormatCode, Insertion};
+///
+/// let code = cblock!("if x {", ["y();"], "}");
+///
+/// let expected = "        if x {\n            y();\n        }";
+/// assert_eq!(expected, code.format_at(&Format::default(), &Insertion::level(2)));
+///
+/// // after "    let a = " on a line
+/// let code = cblock!("match x {", ["_ => 1,"], "};");
+/// let at = Insertion::indent("    ").set_column(12);
+/// let expected = "match x {\n        _ => 1,\n    };";
+/// assert_eq!(expected, code.format_at(&Format::default(), &at));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Insertion {
+    indent: BaseIndent,
+    column: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BaseIndent {
+    /// Number of levels of indentation of the format
+    Level(usize),
+    /// Indentation text
+    Text(Cow<'static, str>),
+}
+
+impl Default for BaseIndent {
+    fn default() -> Self {
+        Self::Level(0)
+    }
+}
+
+impl Insertion {
+    /// Insert whole lines, indented by the number of levels of indentation of the format
+    pub fn level(level: usize) -> Self {
+        Self {
+            indent: BaseIndent::Level(level),
+            column: None,
+        }
+    }
+    /// Insert whole lines, each starting with the indentation text
+    pub fn indent<T: Into<Cow<'static, str>>>(indent: T) -> Self {
+        Self {
+            indent: BaseIndent::Text(indent.into()),
+            column: None,
+        }
+    }
+    /// Insert in the middle of a line, after text that ends at the column.
+    ///
+    /// The first line is not indented, and layout decisions on it start at the column
+    #[inline]
+    pub fn set_column(mut self, column: usize) -> Self {
+        self.column = Some(column);
+        self
+    }
+    /// The column where the first line continues, if inserting in the middle of a line
+    #[inline]
+    pub fn column(&self) -> Option<usize> {
+        self.column
+    }
+    /// The indentation text added before the lines with the format
+    pub fn indent_text(&self, format: &Format) -> Cow<'static, str> {
+        match &self.indent {
+            BaseIndent::Level(level) => {
+                let unit = match format.indent {
+                    i if i < 0 => "\t".to_string(),
+                    i => " ".repeat(i as usize),
+                };
+                unit.repeat(*level).into()
+            }
+            BaseIndent::Text(text) => text.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+    use alloc::vec;
+
+    use super::*;
+    use crate::{cblock, clist, Code, FormatCode, InlinePolicy};
+
+    fn sample() -> Code {
+        cblock!(
+            "fn f() {",
+            [Code::from(""), Code::from("a();"), Code::from("")],
+            "}"
+        )
+        .into()
+    }
+
+    #[test]
+    fn whole_lines() {
+        let code = sample();
+        let expected = "  fn f() {\n\n      a();\n\n  }";
+        assert_eq!(
+            expected,
+            code.format_at(&Format::default(), &Insertion::indent("  "))
+        );
+        let expected = "\t\tfn f() {\n\n\t\t\ta();\n\n\t\t}";
+        assert_eq!(
+            expected,
+            code.format_at(&Format::indent_tab(), &Insertion::level(2))
+        );
+        let expected = vec!["  fn f() {", "    a();", "  }"];
+        let format = Format::indent(2).set_trim_blank_lines(true);
+        assert_eq!(expected, code.format_vec_at(&format, &Insertion::level(1)));
+        assert_eq!(
+            code.format(),
+            code.format_at(&Format::default(), &Insertion::default())
+        );
+    }
+
+    #[test]
+    fn mid_line() {
+        let code: Code = clist!("," => ["a", "b"]).into();
+        let at = Insertion::indent("  ").set_column(6);
+        assert_eq!("a,\n  b,", code.format_at(&Format::default(), &at));
+
+        let code: Code = cblock!("[", ["a", "b"], "]")
+            .inline_policy(InlinePolicy::custom(|_, ctx| ctx.column() < 10))
+            .into();
+        let at = Insertion::default().set_column(9);
+        assert_eq!("[ a b ]", code.format_at(&Format::default(), &at));
+        let at = Insertion::indent("    ").set_column(10);
+        let expected = "[\n        a\n        b\n    ]";
+        assert_eq!(expected, code.format_at(&Format::default(), &at));
+    }
+
+    #[test]
+    fn emitter() {
+        let code = sample();
+        let format = Format::default();
+        let mut out = String::new();
+        let mut sink = crate::TextSink::new(&mut out);
+        let at = Insertion::level(1).set_column(3);
+        let mut emitter = crate::Emitter::new_at(&format, &mut sink, &at);
+        assert_eq!(3, emitter.column());
+        code.emit(&crate::RenderContext::new(&format), &mut emitter);
+        emitter.finish().unwrap();
+        assert_eq!("fn f() {\n\n        a();\n\n    }", out);
+    }
+}
@@ -0,0 +1,329 @@
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+use core::cell::RefCell;
+use core::hash::{Hash, Hasher};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use crate::{Code, Concat, CustomCode, Emitter, Format, FormatCode, RenderContext};
+
+/// A function that builds code
+pub type LazyFn = Arc<dyn Fn() -> Code + Send + Sync>;
+
+/// Code built by a function when it is needed, for example when it is emitted
+///
+/// This is useful for code that is expensive to build and may not be emitted,
+/// or code that depends on information known after the rest of the tree is assembled.
+///
+/// The function is called once each time the code is emitted, and the code is reused for
+/// the layout decisions and the size hints of that render. Outside of a render, such as
+/// for [`Code::is_empty`] and [`Code::should_inline`], it is called every time.
+/// Use [`Lazy::memoized`] to only call it once.
+///
+/// Clones share the function and the memoized code. Lazy code is compared and hashed by
+/// the address of the function, so it is equal to its clones, memoized or not
+///
+/// # Examples
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use codize::{cblock, Concat, Lazy};
+///
+/// let names = Arc::new(Mutex::new(Vec::new()));
+/// let code = cblock!("enum Name {", [
+///     Lazy::new({
+///         let names = Arc::clone(&names);
+///         move || {
+///             let names = names.lock().unwrap();
+///             Concat::new(names.iter().map(|n| format!("{n},")))
+///         }
+///     }).memoized()
+/// ], "}");
+///
+/// names.lock().unwrap().extend(["Foo", "Bar"]);
+/// assert_eq!("enum Name {\n    Foo,\n    Bar,\n}", code.to_string());
+/// ```
+#[derive(Clone)]
+pub struct Lazy {
+    init: LazyFn,
+    /// The code built by the function, if memoized
+    cache: Option<Arc<OnceLock<Code>>>,
+}
+
+impl Lazy {
+    /// Create lazy code from a function
+    pub fn new<F, T>(f: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+        T: Into<Code>,
+    {
+        Self {
+            init: Arc::new(move || f().into()),
+            cache: None,
+        }
+    }
+
+    /// Create lazy code that concatenates the sections from the iterator.
+    /// The iterator is consumed once, so this is always memoized
+    pub fn concat<TBody>(body: TBody) -> Self
+    where
+        TBody: IntoIterator + Send + 'static,
+        TBody::Item: Into<Code>,
+    {
+        let body = Mutex::new(Some(body));
+        Self::new(move || {
+            let body = body.lock().unwrap_or_else(PoisonError::into_inner).take();
+            Concat::new(body.into_iter().flatten())
+        })
+        .memoized()
+    }
+
+    /// Only call the function once, and reuse the code afterwards
+    pub fn memoized(mut self) -> Self {
+        if self.cache.is_none() {
+            self.cache = Some(Arc::new(OnceLock::new()));
+        }
+        self
+    }
+
+    /// Get if the code is memoized and already built
+    #[inline]
+    pub fn is_evaluated(&self) -> bool {
+        self.cache
+            .as_ref()
+            .is_some_and(|cache| cache.get().is_some())
+    }
+
+    /// Get the code, calling the function if it is not memoized or not built yet
+    pub fn evaluate(&self) -> Cow<'_, Code> {
+        match &self.cache {
+            Some(cache) => Cow::Borrowed(cache.get_or_init(|| (self.init)())),
+            None => Cow::Owned(self.build()),
+        }
+    }
+
+    /// Call the function, or reuse the code built in the current render
+    fn build(&self) -> Code {
+        let Some(built) = RENDER.with_borrow(Clone::clone) else {
+            return (self.init)();
+        };
+        let key = Arc::as_ptr(&self.init).cast::<()>() as usize;
+        let slot = {
+            let mut built = built.lock().unwrap_or_else(PoisonError::into_inner);
+            let (_, slot) = built
+                .entry(key)
+                .or_insert_with(|| (Arc::clone(&self.init), Slot::default()));
+            Arc::clone(slot)
+        };
+        // the function is called without the lock, since it may emit code itself
+        Code::Shared(Arc::clone(slot.get_or_init(|| Arc::new((self.init)()))))
+    }
+}
+
+/// Code built by a lazy function in a render
+type Slot = Arc<OnceLock<Arc<Code>>>;
+
+/// The lazy functions called in a render, by address.
+/// The function is kept so the address is not reused
+type Built = Arc<Mutex<HashMap<usize, (LazyFn, Slot)>>>;
+
+std::thread_local! {
+    /// The render running on this thread
+    static RENDER: RefCell<Option<Built>> = const { RefCell::new(None) };
+}
+
+/// Lazy code built in one render, so that code that is not memoized is only built once
+#[derive(Clone, Default)]
+pub(crate) struct RenderScope {
+    built: Built,
+}
+
+impl RenderScope {
+    /// Get the render running on this thread, to continue it on other threads
+    #[cfg(feature = "rayon")]
+    pub(crate) fn current() -> Option<Self> {
+        RENDER.with_borrow(|render| render.clone().map(|built| Self { built }))
+    }
+
+    /// Run the function as part of this render, unless a render is already running
+    /// on this thread
+    pub(crate) fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        /// Ends the render on this thread, even if the function panics
+        struct Exit;
+        impl Drop for Exit {
+            fn drop(&mut self) {
+                RENDER.with_borrow_mut(|render| *render = None);
+            }
+        }
+
+        let entered = RENDER.with_borrow_mut(|render| match render {
+            Some(_) => false,
+            None => {
+                *render = Some(Arc::clone(&self.built));
+                true
+            }
+        });
+        if !entered {
+            return f();
+        }
+        let _exit = Exit;
+        f()
+    }
+}
+
+impl core::fmt::Debug for Lazy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Lazy")
+            .field("code", &self.cache.as_ref().and_then(|cache| cache.get()))
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for Lazy {
+    fn eq(&self, other: &Self) -> bool {
+        // the memoized code is built by the same function, so it is not compared
+        core::ptr::addr_eq(Arc::as_ptr(&self.init), Arc::as_ptr(&other.init))
+    }
+}
+
+impl Eq for Lazy {}
+
+impl Hash for Lazy {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.init).cast::<()>().hash(state);
+    }
+}
+
+impl FormatCode for Lazy {
+    fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+        self.evaluate().emit(ctx, emitter)
+    }
+
+    fn size_hint(&self) -> usize {
+        self.evaluate().size_hint()
+    }
+
+    fn byte_size_hint(&self, format: &Format) -> usize {
+        self.evaluate().byte_size_hint(format)
+    }
+
+    fn flat_width(&self, format: &Format) -> usize {
+        self.evaluate().flat_width(format)
+    }
+}
+
+impl CustomCode for Lazy {
+    fn should_inline_in(&self, ctx: &RenderContext<'_>) -> bool {
+        self.evaluate().should_inline_in(ctx)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.evaluate().is_empty()
+    }
+
+    fn has_hard_break(&self) -> bool {
+        self.evaluate().has_hard_break()
+    }
+
+    fn ends_with_hard_break(&self) -> bool {
+        self.evaluate().ends_with_hard_break()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use indoc::indoc;
+
+    use super::*;
+    use crate::{cblock, clist};
+
+    fn counted(count: &Arc<AtomicUsize>) -> Lazy {
+        let count = Arc::clone(count);
+        Lazy::new(move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            cblock!("{", ["a"], "}")
+        })
+    }
+
+    #[test]
+    fn not_emitted() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let code = cblock!("fn a() {", [counted(&count)], "}");
+        let cloned = code.clone();
+        assert_eq!(code, cloned);
+        drop(code);
+        drop(cloned);
+        assert_eq!(0, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn memoized() {
+        let expected = indoc! {"
+            fn a() {
+                {
+                    a
+                }
+            }"};
+        let count = Arc::new(AtomicUsize::new(0));
+        let code = cblock!("fn a() {", [counted(&count)], "}");
+        assert_eq!(expected, code.to_string());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        assert_eq!(expected, code.format_with(&Format::indent(4)));
+        assert_eq!(2, count.load(Ordering::SeqCst));
+
+        count.store(0, Ordering::SeqCst);
+        let lazy = counted(&count).memoized();
+        assert!(!lazy.is_evaluated());
+        let code = cblock!("fn a() {", [lazy.clone()], "}");
+        assert_eq!(expected, code.to_string());
+        assert_eq!(expected, code.clone().to_string());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        assert!(lazy.is_evaluated());
+        assert_eq!(3, Code::from(lazy).size_hint());
+    }
+
+    #[test]
+    fn eq_hash() {
+        use core::hash::BuildHasher;
+        use std::collections::hash_map::RandomState;
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let lazy = counted(&count);
+        let memoized = lazy.clone().memoized();
+        assert_eq!(lazy, memoized);
+        assert_eq!(memoized, lazy.clone().memoized());
+        assert_ne!(lazy, counted(&count));
+        let state = RandomState::new();
+        assert_eq!(state.hash_one(&lazy), state.hash_one(&memoized));
+        assert_eq!(0, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn empty() {
+        let code = clist!("," => [
+            "a",
+            Lazy::new(Concat::empty),
+            Lazy::concat(["b", "c"]),
+            Lazy::concat(Vec::<Code>::new()),
+        ]);
+        assert_eq!("a,\nb\nc,", code.to_string());
+        assert!(Code::from(Lazy::new(Concat::empty)).is_empty());
+    }
+
+    #[test]
+    fn inline() {
+        let lazy = Lazy::new(|| clist!("," => ["a", "b"]).inlined());
+        assert_eq!(2, lazy.size_hint());
+        let code = cblock!("f(", [lazy.clone()], ")");
+        assert!(code.should_inline());
+        assert_eq!("f( a, b )", code.to_string());
+
+        let comment = Lazy::new(|| Code::hard_line("// a"));
+        let code = cblock!("f(", [comment], ")").inlined();
+        assert!(!code.should_inline());
+        assert_eq!("f(\n    // a\n)", code.to_string());
+    }
+}
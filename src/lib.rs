@@ -1,46 +1,160 @@
 #![doc = include_str!("../README.md")]
+#![no_std]
 
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate std;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+mod arena;
+#[cfg(feature = "tokio")]
+mod async_io;
+pub use arena::{ArenaCode, CodeArena, CodeId};
 mod block;
 pub use block::Block;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
+pub use cache::{CacheStats, RenderCache};
 mod concat;
 pub use concat::Concat;
+mod connect;
+pub use connect::Connect;
+mod custom;
+pub use custom::{CustomCode, DynCode};
+mod context;
+pub use context::{NodeRef, RenderContext};
+mod emit;
+pub use emit::{Emitter, Sink, TextSink};
+mod inline;
+pub use inline::{InlineFn, InlinePolicy};
+mod insertion;
+pub use insertion::Insertion;
+#[cfg(feature = "std")]
+mod lazy;
+#[cfg(feature = "std")]
+pub use lazy::{Lazy, LazyFn};
 mod list;
+#[cfg(feature = "rayon")]
+mod parallel;
 pub use list::{List, Trailing};
+mod render;
+#[cfg(feature = "std")]
+mod stream;
+#[cfg(feature = "std")]
+pub use stream::{CodeStream, Scope};
+mod tree;
+mod width;
+mod writer;
+#[cfg(feature = "unicode-width")]
+pub use width::UnicodeWidth;
+pub use width::{CharWidth, Measure, MeasureWidth};
+pub use writer::{CodeWriter, ListScope};
+
+use inline::Summaries;
+use tree::CodeTree;
 
 /// Code structure
 ///
 /// You should use the macros or `into` conversion instead of constructing this directly.
-#[derive(Debug, Clone, PartialEq)]
+///
+/// Emitting, comparing, cloning and dropping code do not recurse on the call stack,
+/// so deeply nested code is safe. Custom code ([`Code::Custom`]) and custom inline conditions
+/// are called as-is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Code {
     /// A line of code.
-    Line(String),
+    Line(Cow<'static, str>),
+    /// A line that must end its line, such as a line comment, a verbatim string or a preprocessor
+    /// directive.
+    ///
+    /// Nothing is connected after a hard line, and any [`Block`] or [`List`] containing it
+    /// will never be inlined, regardless of the inline condition. The text is emitted as-is,
+    /// so it can contain multiple lines. Use [`Code::hard_line`] to create one.
+    HardLine(Cow<'static, str>),
     /// A block of code. See [`Block`]
     Block(Box<Block>),
     /// Concatenation of multiple code sections. See [`Concat`]
     Concat(Concat),
     /// A list of code segments with separator. See [`List`]
     List(List),
+    /// A code segment connected to the previous line. See [`Code::connect_with`]
+    Connected(Connect, Box<Code>),
+    /// A user-defined code structure. See [`CustomCode`]
+    ///
+    /// Custom code cannot be serialized
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(Box<dyn CustomCode>),
+    /// Code shared by multiple trees, so it is stored once and cloned without copying.
+    /// See [`Code::shared`]
+    ///
+    /// The shared code is indented by where it is emitted. When serialized, each
+    /// occurrence is written out in full
+    Shared(Arc<Code>),
 }
 
 impl From<String> for Code {
     fn from(x: String) -> Self {
-        Code::Line(x)
+        Code::Line(Cow::Owned(x))
+    }
+}
+
+impl From<&String> for Code {
+    fn from(x: &String) -> Self {
+        Code::Line(Cow::Owned(x.clone()))
     }
 }
 
 impl From<&str> for Code {
     fn from(x: &str) -> Self {
-        Code::Line(x.to_owned())
+        Code::Line(Cow::Owned(x.to_string()))
+    }
+}
+
+impl From<Arc<Code>> for Code {
+    #[inline]
+    fn from(x: Arc<Code>) -> Self {
+        Code::Shared(x)
+    }
+}
+
+impl From<Cow<'static, str>> for Code {
+    fn from(x: Cow<'static, str>) -> Self {
+        Code::Line(x)
     }
 }
 
 /// Formatting options
 #[derive(derivative::Derivative)]
-#[derivative(Debug, PartialEq, Default)]
+#[derivative(Debug, Clone, PartialEq, Default)]
 pub struct Format {
     /// The number of spaces to indent per level. `-1` to use tabs
     #[derivative(Default(value = "4"))]
     pub indent: i32,
+    /// The number of columns a tab takes when measuring width
+    #[derivative(Default(value = "4"))]
+    pub tab_width: usize,
+    /// How to measure the display width of text. See [`MeasureWidth`]
+    #[derivative(Debug = "ignore")]
+    pub measure: Measure,
+    /// Emit everything on one line, without indentation and optional spaces.
+    ///
+    /// Whitespace between code segments is only kept where it is required, such as between
+    /// identifiers. The text of each segment is kept as-is.
+    /// [`Code::HardLine`]s are still on their own lines, so the output could still have multiple lines
+    /// if the code has hard breaks. Use [`Code::has_hard_break`] to check for that
+    pub minify: bool,
+    /// The maximum number of consecutive blank lines. Longer runs of blank lines are collapsed.
+    /// `None` for no limit
+    pub max_blank_lines: Option<usize>,
+    /// Remove blank lines right after the start or right before the end of a [`Block`]
+    pub trim_blank_lines: bool,
 }
 
 impl Format {
@@ -63,6 +177,48 @@ impl Format {
     pub fn set_indent_tab(self) -> Self {
         self.set_indent(-1)
     }
+    /// Set minify. See [`Format::minify`](#structfield.minify)
+    pub fn minify() -> Self {
+        Self::default().set_minify(true)
+    }
+    /// Set minify. See [`Format::minify`](#structfield.minify)
+    #[inline]
+    pub fn set_minify(mut self, minify: bool) -> Self {
+        self.minify = minify;
+        self
+    }
+    /// Set the maximum number of consecutive blank lines
+    #[inline]
+    pub fn set_max_blank_lines(mut self, max: usize) -> Self {
+        self.max_blank_lines = Some(max);
+        self
+    }
+    /// Set if blank lines at the start and end of blocks should be removed
+    #[inline]
+    pub fn set_trim_blank_lines(mut self, trim: bool) -> Self {
+        self.trim_blank_lines = trim;
+        self
+    }
+    /// Set how to measure the display width of text
+    #[inline]
+    pub fn set_measure<T: MeasureWidth + 'static>(mut self, measure: T) -> Self {
+        self.measure = Measure::Custom(Arc::new(measure));
+        self
+    }
+    /// Measure the display width of the text
+    #[inline]
+    pub fn width(&self, text: &str) -> usize {
+        self.measure.width(text)
+    }
+    /// The display width of one level of indentation
+    #[inline]
+    pub fn indent_width(&self) -> usize {
+        if self.indent < 0 {
+            self.tab_width
+        } else {
+            self.indent as usize
+        }
+    }
 }
 
 /// Enable different formatting options for [`Code`] structures
@@ -74,113 +230,342 @@ pub trait FormatCode {
 
     /// Emit self with the format as a string
     fn format_with(&self, format: &Format) -> String {
-        self.format_vec_with(format).join("\n")
+        render::scoped(|| {
+            let byte_size_hint = self.byte_size_hint(format);
+            let mut out = String::with_capacity(byte_size_hint);
+            let mut sink = TextSink::new(&mut out);
+            let mut emitter = Emitter::new(format, &mut sink);
+            self.emit(&RenderContext::new(format), &mut emitter);
+            // writing to a string never fails
+            let _ = emitter.finish();
+            // ensure no reallocation
+            #[cfg(test)]
+            if byte_size_hint > 0 {
+                assert_eq!(out.capacity(), byte_size_hint);
+            }
+            out
+        })
     }
     /// Emit self with the format as a vector of lines
     fn format_vec_with(&self, format: &Format) -> Vec<String> {
-        let size_hint = self.size_hint();
-        let mut out = match size_hint {
-            0 => Vec::new(),
-            n => Vec::with_capacity(n),
-        };
-        self.format_into_vec_with(format, &mut out, false, "");
-        // ensure no reallocation
-        #[cfg(test)]
-        if size_hint > 0 {
-            assert_eq!(out.capacity(), size_hint);
-        }
-        out
-    }
-    /// Emit self with the format in the given output context
-    fn format_into_vec_with(
-        &self,
-        format: &Format,
-        out: &mut Vec<String>,
-        connect: bool,
-        indent: &str,
-    );
+        render::scoped(|| {
+            let size_hint = self.size_hint();
+            let mut out = match size_hint {
+                0 => Vec::new(),
+                n => Vec::with_capacity(n),
+            };
+            let mut emitter = Emitter::new(format, &mut out);
+            self.emit(&RenderContext::new(format), &mut emitter);
+            // writing to a vector never fails
+            let _ = emitter.finish();
+            // ensure no reallocation
+            #[cfg(test)]
+            if size_hint > 0 {
+                assert_eq!(out.capacity(), size_hint);
+            }
+            out
+        })
+    }
+    /// Emit self with the format as a string, to be inserted into existing text.
+    /// See [`Insertion`]
+    fn format_at(&self, format: &Format, insertion: &Insertion) -> String {
+        render::scoped(|| {
+            let mut out = String::with_capacity(self.byte_size_hint(format));
+            let mut sink = TextSink::new(&mut out);
+            let mut emitter = Emitter::new_at(format, &mut sink, insertion);
+            self.emit(&RenderContext::new(format), &mut emitter);
+            // writing to a string never fails
+            let _ = emitter.finish();
+            out
+        })
+    }
+    /// Emit self with the format as a vector of lines, to be inserted into existing text.
+    /// See [`Insertion`]
+    fn format_vec_at(&self, format: &Format, insertion: &Insertion) -> Vec<String> {
+        render::scoped(|| {
+            let mut out = Vec::with_capacity(self.size_hint());
+            let mut emitter = Emitter::new_at(format, &mut out, insertion);
+            self.emit(&RenderContext::new(format), &mut emitter);
+            // writing to a vector never fails
+            let _ = emitter.finish();
+            out
+        })
+    }
+    /// Emit self in the given context to the emitter
+    ///
+    /// The first text pushed is joined to the previous line as decided by the parent,
+    /// through [`Emitter::connect`] or [`Emitter::begin_line`]
+    fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>);
     /// Upperbound for the line count of the code for pre-allocating. Return 0 to skip
     fn size_hint(&self) -> usize;
+    /// Upperbound for the length in bytes of the code emitted with the format,
+    /// not including the indentation added by the parents. Return 0 to skip
+    fn byte_size_hint(&self, format: &Format) -> usize {
+        let _ = format;
+        0
+    }
+    /// The display width of the code if it is emitted on one line, measured with the format
+    fn flat_width(&self, format: &Format) -> usize;
 }
 
-impl std::fmt::Display for Code {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.format())
+impl core::fmt::Display for Code {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        display(self, f)
     }
 }
 
-impl FormatCode for Code {
-    fn format_into_vec_with(
-        &self,
-        format: &Format,
-        out: &mut Vec<String>,
-        connect: bool,
-        indent: &str,
-    ) {
-        match self {
-            Code::Line(line) => append_line(out, line, connect, indent),
-            Code::Block(body) => body.format_into_vec_with(format, out, connect, indent),
-            Code::Concat(body) => body.format_into_vec_with(format, out, connect, indent),
-            Code::List(body) => body.format_into_vec_with(format, out, connect, indent),
+/// Emit the code directly to the formatter, with the format from the flags:
+/// - `{:#}` indents with tabs
+/// - `{:2}` indents with 2 spaces, unless `#` is also set
+/// - `{:>8}` indents all lines by 8 more spaces
+pub(crate) fn display<T: FormatCode + ?Sized>(
+    code: &T,
+    f: &mut core::fmt::Formatter<'_>,
+) -> core::fmt::Result {
+    let mut format = if f.alternate() {
+        Format::indent_tab()
+    } else {
+        Format::default()
+    };
+    let base_indent = match (f.align(), f.width()) {
+        (Some(core::fmt::Alignment::Right), Some(width)) => width,
+        (None, Some(width)) if !f.alternate() => {
+            format = format.set_indent(width as i32);
+            0
         }
+        _ => 0,
+    };
+    let insertion = Insertion::indent(" ".repeat(base_indent));
+    let mut sink = TextSink::new(f);
+    let mut emitter = Emitter::new_at(&format, &mut sink, &insertion);
+    code.emit(&RenderContext::new(&format), &mut emitter);
+    emitter.finish()
+}
+
+impl FormatCode for Code {
+    fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+        render::emit(self.into(), ctx, emitter)
     }
 
     fn size_hint(&self) -> usize {
-        match self {
-            Code::Line(_) => 1,
-            Code::Block(body) => body.size_hint(),
-            Code::Concat(body) => body.size_hint(),
-            Code::List(body) => body.size_hint(),
-        }
+        tree::size_hint(CodeTree, self.into())
+    }
+
+    fn byte_size_hint(&self, format: &Format) -> usize {
+        tree::byte_size_hint(core::slice::from_ref(self), 0, format)
+    }
+
+    fn flat_width(&self, format: &Format) -> usize {
+        width::flat_width(CodeTree, self.into(), format)
     }
 }
 
-/// Helper function to append one line to the output within the given context
-pub(crate) fn append_line(out: &mut Vec<String>, line: &str, connect: bool, indent: &str) {
-    if connect {
-        if let Some(last) = out.last_mut() {
-            if !last.is_empty() && last != indent {
-                last.push(' ');
-            }
-            last.push_str(line.as_ref());
-            return;
+/// Check if a line is blank (only whitespaces)
+#[inline]
+pub(crate) fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+/// Check if a space is required to join 2 segments when minifying,
+/// so tokens at the boundary don't merge into one
+pub(crate) fn requires_space(left: &str, right: &str) -> bool {
+    let (Some(l), Some(r)) = (left.chars().next_back(), right.chars().next()) else {
+        return false;
+    };
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    let is_operator = |c: char| "+-*/%<>=!&|^~?:.#@".contains(c);
+    (is_word(l) && is_word(r)) || (is_operator(l) && is_operator(r))
+}
+
+impl Code {
+    /// Create a [`Code::HardLine`]
+    ///
+    /// # Examples
+    /// ```
+    /// use codize::{cblock, Code};
+    ///
+    /// let expected =
+    /// "let x = {
+    ///     // one
+    ///     1
+    /// };";
+    ///
+    /// // the comment prevents the block from being inlined
+    /// let code = cblock!("let x = {", [Code::hard_line("// one"), "1"], "};").inlined();
+    /// assert_eq!(expected, code.to_string());
+    /// ```
+    pub fn hard_line<T: ToString>(line: T) -> Self {
+        Code::HardLine(Cow::Owned(line.to_string()))
+    }
+
+    /// Create a [`Code::Line`] from static text, which is stored without allocating
+    ///
+    /// # Examples
+    /// ```
+    /// use std::borrow::Cow;
+    /// use codize::Code;
+    ///
+    /// assert_eq!(Code::Line(Cow::Borrowed("a();")), Code::borrowed("a();"));
+    /// assert_eq!(Code::from("a();"), Code::borrowed("a();"));
+    /// ```
+    #[inline]
+    pub const fn borrowed(line: &'static str) -> Self {
+        Code::Line(Cow::Borrowed(line))
+    }
+
+    /// Share the code, so cloning it afterwards does not copy the code.
+    /// See [`Code::Shared`]
+    ///
+    /// # Examples
+    /// ```
+    /// use codize::{cblock, cconcat, Code};
+    ///
+    /// let expected =
+    /// "fn a() {
+    ///     if (err) {
+    ///         return;
+    ///     }
+    /// }
+    /// if (err) {
+    ///     return;
+    /// }";
+    ///
+    /// let check = Code::from(cblock!("if (err) {", ["return;"], "}")).shared();
+    /// let code = cconcat![cblock!("fn a() {", [check.clone()], "}"), check];
+    /// assert_eq!(expected, code.to_string());
+    /// ```
+    pub fn shared(self) -> Self {
+        match self {
+            Code::Shared(_) => self,
+            code => Code::Shared(Arc::new(code)),
         }
     }
-    // when making a new line, make sure the previous line is not indented if it's only whitespaces
-    if let Some(last) = out.last_mut() {
-        if last.trim().is_empty() {
-            "".clone_into(last);
+
+    /// Connect the code to the end of the previous line with a space.
+    /// See [`Code::connect_with`]
+    #[inline]
+    pub fn connected(self) -> Self {
+        self.connect_with(Connect::Space)
+    }
+
+    /// Set how the code is connected to the previous line
+    ///
+    /// For blocks, this is the same as [`Block::connect_with`]. Other code is wrapped in
+    /// [`Code::Connected`]
+    ///
+    /// # Examples
+    /// ```
+    /// use codize::{cblock, cconcat, Code, Connect};
+    ///
+    /// let expected =
+    /// "do {
+    ///     x++;
+    /// } while (x < 10);
+    /// foo(() => {
+    ///     bar();
+    /// });";
+    ///
+    /// let code = cconcat![
+    ///     cblock!("do {", ["x++;"], "}"),
+    ///     Code::from("while (x < 10);").connected(),
+    ///     cblock!("foo(() => {", ["bar();"], "}"),
+    ///     Code::from(");").connect_with(Connect::Tight),
+    /// ];
+    /// assert_eq!(expected, code.to_string());
+    /// ```
+    pub fn connect_with(self, mode: Connect) -> Self {
+        match self {
+            Code::Block(block) => block.connect_with(mode).into(),
+            Code::Connected(_, code) => Code::Connected(mode, code),
+            code => Code::Connected(mode, Box::new(code)),
         }
     }
-    if indent.is_empty() {
-        out.push(line.to_owned());
-    } else {
-        out.push(format!("{indent}{line}"));
+
+    /// Get how the code is connected to the previous line
+    pub fn connect_mode(&self) -> &Connect {
+        tree::connect_mode(CodeTree, self.into())
     }
-}
 
-impl Code {
     /// Should the code be displayed in one line
     pub fn should_inline(&self) -> bool {
-        match self {
-            Code::Block(block) => block.should_inline(),
-            Code::List(list) => list.should_inline(),
-            _ => false,
-        }
+        self.should_inline_in(&RenderContext::new(&Format::default()))
+    }
+
+    /// Should the code be displayed in one line, when emitted in the context
+    pub fn should_inline_in(&self, ctx: &RenderContext<'_>) -> bool {
+        inline::should_inline(CodeTree, self.into(), ctx, &mut Summaries::default())
     }
 
     /// Get if this structure will generate any code or not (empty = no code)
     pub fn is_empty(&self) -> bool {
-        match self {
-            Code::Concat(concat) => concat.is_empty(),
-            Code::List(list) => list.is_empty(),
-            _ => false,
+        tree::is_empty(CodeTree, self.into())
+    }
+
+    /// Add a child to the end of the body of a [`Block`], [`List`] or [`Concat`],
+    /// including one inside [`Code::Connected`] or [`Code::Shared`].
+    ///
+    /// Shared code is cloned first if it is shared with other places, so they are not changed.
+    /// Returns the child back if the code is not a container
+    ///
+    /// # Examples
+    /// ```
+    /// use codize::{cblock, Code};
+    ///
+    /// let mut code = Code::from(cblock!("impl Foo {", [], "}"));
+    /// code.push_child("fn a() {}").unwrap();
+    /// code.push_child("fn b() {}").unwrap();
+    /// assert_eq!("impl Foo {\n    fn a() {}\n    fn b() {}\n}", code.to_string());
+    ///
+    /// let mut line = Code::from("x");
+    /// assert_eq!(Err(Code::from("y")), line.push_child("y"));
+    /// ```
+    pub fn push_child<T: Into<Code>>(&mut self, child: T) -> Result<(), Code> {
+        if !matches!(
+            self.unwrapped(),
+            Code::Block(_) | Code::List(_) | Code::Concat(_)
+        ) {
+            return Err(child.into());
         }
+        let mut code = self;
+        loop {
+            code = match code {
+                Code::Block(block) => {
+                    block.push(child);
+                    return Ok(());
+                }
+                Code::List(list) => {
+                    list.push(child);
+                    return Ok(());
+                }
+                Code::Concat(concat) => {
+                    concat.push(child.into());
+                    return Ok(());
+                }
+                Code::Connected(_, inner) => inner,
+                Code::Shared(inner) => Arc::make_mut(inner),
+                _ => unreachable!("checked to be a container"),
+            };
+        }
+    }
+
+    /// Get if the code contains a [`Code::HardLine`] anywhere, which means it cannot be inlined
+    pub fn has_hard_break(&self) -> bool {
+        tree::has_hard_break(CodeTree, self.into())
+    }
+
+    /// Get if the last line emitted by the code is a [`Code::HardLine`],
+    /// which means nothing can be connected after it
+    pub fn ends_with_hard_break(&self) -> bool {
+        tree::ends_with_hard_break(CodeTree, self.into())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec;
+
     use indoc::indoc;
 
     use super::*;
@@ -302,4 +687,367 @@ mod test {
             }"};
         assert_eq!(expected, code.to_string());
     }
+
+    #[test]
+    fn hard_break() {
+        let code: Code = cblock!(
+            "fn main() {",
+            [
+                cblock!("if (foo) {", ["bar();"], "}"),
+                Code::hard_line("// comment"),
+                cblock!("else {", ["baz();"], "}").connected(),
+            ],
+            "}"
+        )
+        .inlined()
+        .into();
+        let expected = indoc! {"
+            fn main() {
+                if (foo) {
+                    bar();
+                }
+                // comment
+                else {
+                    baz();
+                }
+            }"};
+        assert!(code.has_hard_break());
+        assert!(!code.ends_with_hard_break());
+        assert_eq!(expected, code.to_string());
+    }
+
+    #[test]
+    fn hard_break_nested() {
+        fn always(_: &Block) -> bool {
+            true
+        }
+        let code: Code = cblock!(
+            "{",
+            [cblock!("{", [cconcat!["a", Code::hard_line("#if X")]], "}").inline_when(always)],
+            "}"
+        )
+        .inline_when(always)
+        .into();
+        let expected = indoc! {"
+            {
+                {
+                    a
+                    #if X
+                }
+            }"};
+        assert_eq!(expected, code.to_string());
+    }
+
+    #[test]
+    fn minify() {
+        let code = test_case_3();
+        let expected = r#"fn main() {if (foo) {println!("Hello, world!");}else {bar(giz);}}"#;
+        assert_eq!(expected, code.format_with(&Format::minify()));
+
+        let code = test_case_4(|_| false, |_| false);
+        let expected = "while true {let x = 1;let b = {1,2,3};let b = {1,2,3,4};}";
+        assert_eq!(expected, code.format_with(&Format::minify()));
+
+        let code: Code = cconcat![
+            "let x = 1",
+            "- -1;",
+            "",
+            cblock!("return", [clist!("," => ["a", "b"]).always_trail()], ";"),
+        ]
+        .into();
+        assert_eq!(
+            "let x = 1- -1;return a,b,;",
+            code.format_with(&Format::minify())
+        );
+    }
+
+    #[test]
+    fn minify_hard_break() {
+        let code: Code = cblock!(
+            "fn main() {",
+            [
+                cblock!("if (foo) {", ["bar();", Code::hard_line("// comment")], "}"),
+                cblock!("else {", ["baz();"], "}").connected(),
+            ],
+            "}"
+        )
+        .into();
+        let expected = indoc! {"
+            fn main() {if (foo) {bar();
+            // comment
+            }else {baz();}}"};
+        assert_eq!(expected, code.format_with(&Format::minify()));
+
+        let code = cconcat!["a;", Code::hard_line("#if X"), "b;"];
+        assert_eq!("a;\n#if X\nb;", code.format_with(&Format::minify()));
+        let code = clist!("," => ["a", Code::hard_line("// a"), "b"]);
+        assert_eq!("a,\n// a\nb", code.format_with(&Format::minify()));
+    }
+
+    #[test]
+    fn blank_lines() {
+        let code: Code = cblock!(
+            "impl A {",
+            [
+                "",
+                cconcat!(1 => [
+                    cblock!("fn a() {", ["", "a();", "", "", "", "a();", ""], "}"),
+                    cconcat![],
+                    cblock!("fn b() {", [], "}"),
+                    "",
+                ]),
+                "",
+            ],
+            "}"
+        )
+        .into();
+        let expected = indoc! {"
+            impl A {
+
+                fn a() {
+
+                    a();
+
+
+
+                    a();
+
+                }
+
+                fn b() {
+                }
+
+
+
+            }"};
+        assert_eq!(expected, code.to_string());
+        let format = Format::default()
+            .set_max_blank_lines(1)
+            .set_trim_blank_lines(true);
+        let expected = indoc! {"
+            impl A {
+                fn a() {
+                    a();
+
+                    a();
+                }
+
+                fn b() {
+                }
+            }"};
+        assert_eq!(expected, code.format_with(&format));
+    }
+
+    #[test]
+    fn connect_modes() {
+        let code: Code = cblock!(
+            "fn main() {",
+            [
+                cblock!("if (foo)", ["bar();"], "}"),
+                cblock!("else", ["baz();"], "}").connect_with(Connect::NewLine),
+                cblock!("promise.then(() => {", ["foo();"], "})"),
+                cblock!(".then(() => {", ["bar();"], "})").connect_with(Connect::Tight),
+                Code::from(";").connect_with(Connect::Tight),
+                cblock!("x = [", [clist!("," => ["1", "2"])], "]"),
+                Code::from("y = [];").connect_with(Connect::Join("; ".into())),
+            ],
+            "}"
+        )
+        .into();
+        let expected = indoc! {"
+            fn main() {
+                if (foo)
+                    bar();
+                }
+                else
+                    baz();
+                }
+                promise.then(() => {
+                    foo();
+                }).then(() => {
+                    bar();
+                });
+                x = [
+                    1,
+                    2,
+                ]; y = [];
+            }"};
+        assert_eq!(expected, code.to_string());
+    }
+
+    #[test]
+    fn connect_new_line() {
+        let code = clist!("," => [
+            cblock!("{", ["a", "b"], "}"),
+            cblock!("{", ["c", "d"], "}").connect_with(Connect::NewLine),
+        ]);
+        let expected = indoc! {"
+            {
+                a
+                b
+            },
+            {
+                c
+                d
+            },"};
+        assert_eq!(expected, code.to_string());
+        assert_eq!("{a b},{c d}", code.format_with(&Format::minify()));
+    }
+
+    #[test]
+    fn format_eq() {
+        assert_eq!(Format::default(), Format::default());
+        assert_eq!(Format::indent(2), Format::default().set_indent(2));
+        let custom = Format::default().set_measure(CharWidth);
+        assert_eq!(custom, custom.clone());
+        assert_ne!(custom, Format::default());
+        assert_ne!(custom, Format::default().set_measure(CharWidth));
+    }
+
+    #[test]
+    fn flat_width() {
+        let format = Format::default().set_measure(CharWidth);
+        let code = test_case_3();
+        assert_eq!(
+            "fn main() { if (foo) { println!(\"Hello, world!\"); } else { bar(giz); } }".len(),
+            code.flat_width(&format)
+        );
+        let code: Code = cblock!("let x = [", [clist!("," => ["\"键\"", "\"值\""])], "];").into();
+        assert_eq!(
+            "let x = [ \"键\", \"值\" ];".chars().count(),
+            code.flat_width(&format)
+        );
+        #[cfg(feature = "unicode-width")]
+        assert_eq!(
+            "let x = [ \"键\", \"值\" ];".chars().count() + 2,
+            code.flat_width(&Format::default())
+        );
+    }
+
+    #[test]
+    fn static_text_is_borrowed() {
+        let code = Block::borrowed(
+            "fn a() {",
+            [Code::borrowed("a();"), Code::from(format!("{}();", "b"))],
+            "}",
+        );
+        assert!(matches!(code.start, Cow::Borrowed(_)));
+        assert!(matches!(code.end, Cow::Borrowed(_)));
+        assert!(matches!(code.body()[0], Code::Line(Cow::Borrowed(_))));
+        assert!(matches!(code.body()[1], Code::Line(Cow::Owned(_))));
+        assert_eq!(code, cblock!("fn a() {", ["a();", "b();"], "}"));
+        let list = List::borrowed(",", [Code::borrowed("a")]);
+        assert!(matches!(list.separator, Cow::Borrowed(_)));
+        assert!(matches!(clist!("," => ["a"]).separator, Cow::Owned(_)));
+        assert_eq!(
+            "fn a() {
+    a();
+    b();
+}",
+            code.to_string()
+        );
+
+        // other text is copied, including borrowed text and anything displayable
+        let text = String::from("x");
+        let code = cblock!(text.as_str(), [&*text, &text], 1);
+        assert_eq!("x\n    x\n    x\n1", code.to_string());
+        let code = clist!(&text[..0] => [Code::hard_line('y')]);
+        assert_eq!(
+            Code::from(List::borrowed("", [Code::hard_line("y")])),
+            code.into()
+        );
+    }
+
+    #[test]
+    fn shared() {
+        let expected = indoc! {"
+            fn a() {
+                if (err) {
+                    return;
+                }
+                fn b() {
+                    if (err) {
+                        return;
+                    }
+                }
+            }
+            if (err) {
+                return;
+            }"};
+        let check = Code::from(cblock!("if (err) {", ["return;"], "}")).shared();
+        let code: Code = cconcat![
+            cblock!(
+                "fn a() {",
+                [check.clone(), cblock!("fn b() {", [check.clone()], "}")],
+                "}"
+            ),
+            check.clone(),
+        ]
+        .into();
+        assert_eq!(expected, code.to_string());
+        assert!(code.size_hint() >= expected.lines().count());
+
+        let cloned = code.clone();
+        assert_eq!(code, cloned);
+        let (Code::Concat(a), Code::Concat(b)) = (&code, &cloned) else {
+            unreachable!()
+        };
+        let (Code::Shared(a), Code::Shared(b)) = (&a[1], &b[1]) else {
+            unreachable!()
+        };
+        assert!(Arc::ptr_eq(a, b));
+        assert_eq!(7, Arc::strong_count(a));
+    }
+
+    #[test]
+    fn shared_connect() {
+        let expected = "do {\n    x++;\n} while (x < 10);";
+        let cond = Code::from("while (x < 10);").connected().shared();
+        let code = cconcat![cblock!("do {", ["x++;"], "}"), cond.clone()];
+        assert_eq!(expected, code.to_string());
+        let code = clist!("," => [cblock!("{", ["a"], "}"), cond]);
+        assert_eq!("{\n    a\n}, while (x < 10);,", code.to_string());
+    }
+
+    #[test]
+    fn display_flags() {
+        let code = test_case_3();
+        assert_eq!(code.format(), format!("{code}"));
+        assert_eq!(code.format_with(&Format::indent(2)), format!("{code:2}"));
+        assert_eq!(code.format_with(&Format::indent_tab()), format!("{code:#}"));
+        assert_eq!(
+            code.format_with(&Format::indent_tab()),
+            format!("{code:#2}")
+        );
+
+        let code = cblock!("{", ["a", "", "b"], "}");
+        let expected = "  {\n      a\n\n      b\n  }";
+        assert_eq!(expected, format!("{code:>2}"));
+        let expected = "  {\n  \ta\n\n  \tb\n  }";
+        assert_eq!(expected, format!("{code:>#2}"));
+        let list = clist!("," => ["a", "b"]);
+        assert_eq!("    a,\n    b,", format!("{list:>4}"));
+    }
+
+    #[test]
+    fn push_child() {
+        let mut code = Code::from(clist!("," => ["a"]).inlined()).connected();
+        code.push_child("b").unwrap();
+        assert_eq!("a, b", code.to_string());
+
+        let shared = Code::from(Concat::new(["a"])).shared();
+        let mut code = shared.clone();
+        code.push_child("b").unwrap();
+        assert_eq!("a", shared.to_string());
+        assert_eq!("a\nb", code.to_string());
+
+        // not cloned if the child cannot be added
+        let mut code = Code::hard_line("// a").shared();
+        let other = code.clone();
+        assert_eq!(Err(Code::from("b")), code.push_child("b"));
+        let (Code::Shared(a), Code::Shared(b)) = (&code, &other) else {
+            unreachable!()
+        };
+        assert!(Arc::ptr_eq(a, b));
+    }
 }
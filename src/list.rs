@@ -1,22 +1,29 @@
-use crate::{Code, Concat, Format, FormatCode};
+use alloc::borrow::Cow;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use crate::inline::{InlineTarget, Summaries};
+use crate::tree::CodeTree;
+use crate::{Code, Concat, Emitter, Format, FormatCode, InlinePolicy, NodeRef, RenderContext};
 
 /// A list of code segments separated by a separator
-#[derive(derivative::Derivative)]
-#[derivative(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct List {
     /// The items in the list
-    concat_body: Concat,
+    #[cfg_attr(feature = "serde", serde(rename = "body"))]
+    pub(crate) concat_body: Concat,
     /// The separator between the items
-    pub separator: String,
+    pub separator: Cow<'static, str>,
     /// The trailing mode
     pub trailing: Trailing,
     /// When to inline
-    #[derivative(Debug = "ignore", PartialEq = "ignore")]
-    inline_condition: Option<fn(&List) -> bool>,
+    pub(crate) inline_policy: InlinePolicy<List>,
 }
 
 /// Trailing mode for a code list
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Trailing {
     /// Add trailing separator if the list is split into multiple lines
     IfMultiLine,
@@ -29,26 +36,38 @@ pub enum Trailing {
 impl List {
     /// Create a new empty code list
     pub fn empty<TSep: ToString>(sep: TSep) -> Self {
-        Self {
-            separator: sep.to_string(),
-            concat_body: Concat::empty(),
-            trailing: Trailing::IfMultiLine,
-            inline_condition: None,
-        }
+        Self::from_parts(sep.to_string(), Concat::empty())
     }
 
     /// Create a new code list
+    ///
+    /// The body is taken from any iterator. Lists are not collected with [`FromIterator`],
+    /// since the separator is needed too
     pub fn new<TSep, TBody>(sep: TSep, body: TBody) -> Self
     where
         TSep: ToString,
         TBody: IntoIterator,
         TBody::Item: Into<Code>,
     {
+        Self::from_parts(sep.to_string(), Concat::new(body))
+    }
+
+    /// Create a new code list with a static separator, which is stored without allocating
+    pub fn borrowed<TBody>(sep: &'static str, body: TBody) -> Self
+    where
+        TBody: IntoIterator,
+        TBody::Item: Into<Code>,
+    {
+        Self::from_parts(sep, Concat::new(body))
+    }
+
+    /// Create a list from the separator and body as they are stored
+    pub(crate) fn from_parts<TSep: Into<Cow<'static, str>>>(sep: TSep, body: Concat) -> Self {
         Self {
-            separator: sep.to_string(),
-            concat_body: Concat::new(body),
+            separator: sep.into(),
+            concat_body: body,
             trailing: Trailing::IfMultiLine,
-            inline_condition: None,
+            inline_policy: InlinePolicy::Intrinsic,
         }
     }
 
@@ -64,43 +83,141 @@ impl List {
         self
     }
 
-    /// Set a condition for displaying the block as one line
-    pub fn inline_when(mut self, condition: fn(&List) -> bool) -> Self {
-        self.inline_condition = Some(condition);
-        self
+    /// Set a condition for displaying the list as one line
+    #[inline]
+    pub fn inline_when<F>(self, condition: F) -> Self
+    where
+        F: Fn(&List) -> bool + Send + Sync + 'static,
+    {
+        self.inline_when_in(move |x, _| condition(x))
+    }
+
+    /// Set a condition for displaying the list as one line, which can depend on the context
+    /// where the list is emitted. See [`RenderContext`]
+    #[inline]
+    pub fn inline_when_in<F>(self, condition: F) -> Self
+    where
+        F: Fn(&List, &RenderContext<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.inline_policy(InlinePolicy::custom(condition))
     }
 
     /// Set the inline condition to be always true
-    pub fn inlined(mut self) -> Self {
-        self.inline_condition = Some(|_| true);
-        self
+    #[inline]
+    pub fn inlined(self) -> Self {
+        self.inline_policy(InlinePolicy::Always)
     }
 
     /// Set the inline condition to be always false
-    pub fn never_inlined(mut self) -> Self {
-        self.inline_condition = Some(|_| false);
+    #[inline]
+    pub fn never_inlined(self) -> Self {
+        self.inline_policy(InlinePolicy::Never)
+    }
+
+    /// Set when to display the list as one line. See [`InlinePolicy`]
+    pub fn inline_policy(mut self, policy: InlinePolicy<List>) -> Self {
+        self.inline_policy = policy;
         self
     }
 
-    /// Get the body of the block
+    /// Get when to display the list as one line
+    #[inline]
+    pub fn get_inline_policy(&self) -> &InlinePolicy<List> {
+        &self.inline_policy
+    }
+
+    /// Get the items of the list
     #[inline]
     pub fn body(&self) -> &[Code] {
         &self.concat_body
     }
 
+    /// Get the items of the list for changing them
+    #[inline]
+    pub fn body_mut(&mut self) -> &mut Vec<Code> {
+        &mut self.concat_body
+    }
+
+    /// Add an item to the end of the list
+    #[inline]
+    pub fn push<T: Into<Code>>(&mut self, item: T) {
+        self.concat_body.push(item.into());
+    }
+
+    /// Insert an item into the list at the index
+    ///
+    /// # Panics
+    /// If the index is greater than the length of the list
+    #[inline]
+    pub fn insert<T: Into<Code>>(&mut self, index: usize, item: T) {
+        self.concat_body.insert(index, item.into());
+    }
+
+    /// Only keep the items for which the function returns true
+    #[inline]
+    pub fn retain<F: FnMut(&Code) -> bool>(&mut self, f: F) {
+        self.concat_body.retain(f);
+    }
+
     /// Get if the list will generate any code or not (empty = no code)
     #[inline]
     pub fn is_empty(&self) -> bool {
         self.concat_body.is_empty()
     }
 
+    /// Get if any item contains a hard break. See [`Code::HardLine`]
+    #[inline]
+    pub fn has_hard_break(&self) -> bool {
+        self.concat_body.has_hard_break()
+    }
+
+    /// Get if the last item is a hard line. See [`Code::HardLine`]
+    ///
+    /// Items ending with a hard break have the separator put on the next line,
+    /// so only hard lines directly in the list end the list with a hard break
+    pub fn ends_with_hard_break(&self) -> bool {
+        crate::tree::ends_with_hard_break(CodeTree, NodeRef::List(self).into())
+    }
+
+    /// Size hint of the separators put on new lines, without the items
+    pub(crate) fn own_size_hint(&self) -> usize {
+        // separators after items ending with a hard break are put on new lines
+        let separator_lines = self
+            .body()
+            .iter()
+            .filter(|c| !matches!(c, Code::HardLine(_)) && c.ends_with_hard_break())
+            .count();
+        self.concat_body.own_size_hint() + separator_lines
+    }
+
+    /// Byte size hint of the separators at the depth, without the items
+    pub(crate) fn own_byte_size_hint(&self, depth: usize, format: &Format) -> usize {
+        let items = self
+            .body()
+            .iter()
+            .filter(|c| !matches!(c, Code::HardLine(_)))
+            .count();
+        let line = crate::tree::line_byte_size(depth, format);
+        items * self.separator.len() + self.own_size_hint() * line
+    }
+
     /// Should the list be displayed in one line
+    ///
+    /// Always false if any item contains a hard break. See [`Code::HardLine`]
     pub fn should_inline(&self) -> bool {
-        if let Some(condition) = self.inline_condition {
-            condition(self)
-        } else {
-            self.should_inline_intrinsic()
-        }
+        self.should_inline_in(&RenderContext::new(&Format::default()))
+    }
+
+    /// Should the list be displayed in one line, when emitted in the context
+    ///
+    /// Always false if the body contains a hard break. See [`Code::HardLine`]
+    pub fn should_inline_in(&self, ctx: &RenderContext<'_>) -> bool {
+        crate::inline::should_inline(
+            CodeTree,
+            NodeRef::List(self).into(),
+            ctx,
+            &mut Summaries::default(),
+        )
     }
 
     /// Should intrinsicly inline the list
@@ -118,75 +235,45 @@ impl From<List> for Code {
     }
 }
 
-impl std::fmt::Display for List {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.format())
+impl<T: Into<Code>> Extend<T> for List {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.concat_body.extend(iter);
+    }
+}
+
+impl core::fmt::Display for List {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        crate::display(self, f)
+    }
+}
+
+impl InlineTarget for List {
+    #[inline]
+    fn node(&self) -> NodeRef<'_> {
+        NodeRef::List(self)
+    }
+
+    #[inline]
+    fn inline_offset(&self, _: &Format) -> usize {
+        0
     }
 }
 
 impl FormatCode for List {
     fn size_hint(&self) -> usize {
-        self.concat_body.size_hint()
-    }
-
-    fn format_into_vec_with(
-        &self,
-        format: &Format,
-        out: &mut Vec<String>,
-        connect: bool,
-        indent: &str,
-    ) {
-        let should_inline = self.should_inline();
-
-        // if first item is appended
-        // used to check if separator should be added
-        let mut first_appended = false;
-        // should next item be connected to the previous one
-        let mut previous_allow_connect = connect;
-
-        let mut previous_size = out.len();
-        let initial_size = previous_size;
-
-        for code in self.body().iter().filter(|c| !c.is_empty()) {
-            // append separator if needed
-            if let Some(last) = out.last_mut() {
-                if first_appended {
-                    last.push_str(&self.separator);
-                }
-            }
-            let connect = if first_appended {
-                should_inline
-                    || (previous_allow_connect && {
-                        // allow connect if the item is first, not block, or is non-inline block
-                        match code {
-                            Code::Block(b) => !b.should_inline(),
-                            _ => true,
-                        }
-                    })
-            } else {
-                // for first, inline if connect
-                connect
-            };
-            // emit the next item to out
-            code.format_into_vec_with(format, out, connect, indent);
-            // check if next item can be connected
-            // only connect if the current is multi-line
-            let new_size = out.len();
-            previous_allow_connect = new_size > previous_size + 1;
-            previous_size = new_size;
-            first_appended = true;
-        }
+        crate::tree::size_hint(CodeTree, NodeRef::List(self).into())
+    }
 
-        let should_trail = match self.trailing {
-            Trailing::IfMultiLine => previous_size > initial_size + 1,
-            Trailing::Always => true,
-            Trailing::Never => false,
-        };
-        if should_trail {
-            if let Some(last) = out.last_mut() {
-                last.push_str(&self.separator);
-            }
-        }
+    fn byte_size_hint(&self, format: &Format) -> usize {
+        self.own_byte_size_hint(0, format) + crate::tree::byte_size_hint(self.body(), 0, format)
+    }
+
+    fn flat_width(&self, format: &Format) -> usize {
+        crate::width::flat_width(CodeTree, NodeRef::List(self).into(), format)
+    }
+
+    fn emit(&self, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+        crate::render::emit(NodeRef::List(self).into(), ctx, emitter)
     }
 }
 
@@ -235,9 +322,11 @@ macro_rules! clist {
 
 #[cfg(test)]
 mod test {
+    use alloc::string::ToString;
+
     use indoc::indoc;
 
-    use crate::{cblock, Block, Code, List};
+    use crate::{cblock, cconcat, Block, Code, List};
 
     #[test]
     fn empty() {
@@ -328,4 +417,53 @@ mod test {
         assert!(!code.should_inline());
         assert_eq!(expected, code.to_string());
     }
+
+    #[test]
+    fn with_comments() {
+        let expected = indoc! {"
+            // first
+            a,
+            // between
+            b, c,
+            // last"};
+        let code = clist!("," => [
+            Code::hard_line("// first"),
+            "a",
+            Code::hard_line("// between"),
+            clist!("," => ["b", "c"]).inlined(),
+            Code::hard_line("// last"),
+        ])
+        .inlined();
+        assert!(!code.should_inline());
+        assert!(code.ends_with_hard_break());
+        assert_eq!(expected, code.to_string());
+    }
+
+    #[test]
+    fn item_ends_with_comment() {
+        let expected = indoc! {"
+            a
+            // a
+            ,
+            b,"};
+        let code = clist!("," => [cconcat!["a", Code::hard_line("// a")], "b"]);
+        assert!(!code.ends_with_hard_break());
+        assert_eq!(expected, code.to_string());
+    }
+
+    #[test]
+    fn mutate_body() {
+        let mut code = clist!("," => ["b"]).inlined();
+        code.push("c");
+        code.insert(0, "a");
+        code.extend([cblock!("{", [], "}")]);
+        code.retain(|c| !matches!(c, Code::Block(_)));
+        assert_eq!("a, b, c", code.to_string());
+        assert_eq!(
+            code,
+            List::new(",", ('a'..='c').map(|c| c.to_string())).inlined()
+        );
+        code.body_mut().clear();
+        assert!(code.is_empty());
+    }
 }
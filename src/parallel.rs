@@ -0,0 +1,288 @@
+//! Emitting the sections of a top-level concat in parallel
+
+use alloc::vec::Vec;
+
+use rayon::prelude::*;
+
+use crate::emit::{LineBuffer, Tail};
+use crate::lazy::RenderScope;
+use crate::{Code, Concat, Emitter, NodeRef, RenderContext};
+
+/// Minimum number of sections to emit in parallel
+const MIN_SECTIONS: usize = 64;
+/// Minimum number of sections in each chunk
+const MIN_CHUNK: usize = 16;
+
+#[cfg(test)]
+std::thread_local! {
+    /// Number of concats emitted in parallel on this thread, to check the path is taken
+    static EMITTED: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+/// Emit the sections of the concat in parallel, if it is at the start of the output.
+///
+/// The sections are split into chunks before sections that start on a new line.
+/// Each chunk is emitted into separate lines, which are then replayed to the emitter,
+/// so blank lines are collapsed and the last line is connected the same way as
+/// emitting sequentially.
+/// Returns false if the concat is not emitted
+pub(crate) fn emit_concat(
+    concat: &Concat,
+    ctx: &RenderContext<'_>,
+    emitter: &mut Emitter<'_>,
+) -> bool {
+    let format = ctx.format();
+    // when minifying, sections are joined on the same line
+    if format.minify || ctx.depth() > 0 || ctx.parent().is_some() || !emitter.is_at_start() {
+        return false;
+    }
+    let threads = rayon::current_num_threads();
+    if threads < 2 {
+        return false;
+    }
+    let sections: Vec<&Code> = concat.iter().filter(|c| !c.is_empty()).collect();
+    if sections.len() < MIN_SECTIONS {
+        return false;
+    }
+    let target = (sections.len() / (threads * 4)).max(MIN_CHUNK);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for (i, code) in sections.iter().enumerate() {
+        if i - start >= target && starts_on_new_line(code) {
+            chunks.push(&sections[start..i]);
+            start = i;
+        }
+    }
+    chunks.push(&sections[start..]);
+    if chunks.len() < 2 {
+        return false;
+    }
+
+    let ancestors = [NodeRef::Concat(concat)];
+    let ctx = RenderContext::with_ancestors(format, 0, "", 0, &ancestors);
+    let blank_lines = concat.get_blank_lines();
+    // the chunks are part of the same render, so lazy code is built once
+    let render = RenderScope::current().unwrap_or_default();
+    let outputs: Vec<(LineBuffer, Tail)> = chunks
+        .par_iter()
+        .map(|chunk| {
+            render.run(|| {
+                let mut lines = LineBuffer::default();
+                let mut chunk_emitter = Emitter::new(format, &mut lines);
+                for (i, code) in chunk.iter().enumerate() {
+                    if i > 0 {
+                        separate(code, blank_lines, &mut chunk_emitter);
+                    }
+                    crate::render::emit((*code).into(), &ctx, &mut chunk_emitter);
+                }
+                // the last line is kept open, since text can be connected to it
+                let tail = chunk_emitter.into_tail();
+                (lines, tail)
+            })
+        })
+        .collect();
+
+    for (i, (lines, tail)) in outputs.iter().enumerate() {
+        if i > 0 {
+            separate(chunks[i][0], blank_lines, emitter);
+        }
+        emitter.replay(lines, tail);
+    }
+    #[cfg(test)]
+    EMITTED.with(|count| count.set(count.get() + 1));
+    true
+}
+
+/// Separate the section from the previous one, the same way as [`crate::render`]
+fn separate(code: &Code, blank_lines: usize, emitter: &mut Emitter<'_>) {
+    if !code.connect_mode().is_connected() {
+        for _ in 0..blank_lines {
+            emitter.begin_line();
+            emitter.push_text("");
+        }
+    }
+    emitter.begin_line();
+}
+
+/// Get if the code always starts on a new line in a concat, so nothing before it
+/// affects how it is emitted. Custom code is assumed to connect
+fn starts_on_new_line(mut code: &Code) -> bool {
+    loop {
+        code = match code {
+            Code::Line(_) | Code::HardLine(_) => return true,
+            Code::Block(block) => return !block.connect.is_connected(),
+            Code::Connected(mode, _) if mode.is_connected() => return false,
+            Code::Connected(_, inner) => inner,
+            Code::Concat(concat) => match concat.iter().find(|c| !c.is_empty()) {
+                Some(first) => first,
+                None => return true,
+            },
+            Code::List(list) => match list.body().iter().find(|c| !c.is_empty()) {
+                Some(first) => first,
+                None => return true,
+            },
+            Code::Shared(inner) => inner,
+            Code::Custom(_) => return false,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+
+    use super::*;
+    use crate::{cblock, cconcat, clist, Connect, Format, FormatCode};
+
+    /// Emit the code without the parallel path, which is only taken at depth 0
+    fn sequential(code: &Code, format: &Format) -> String {
+        let mut out = String::new();
+        let mut sink = crate::TextSink::new(&mut out);
+        let mut emitter = Emitter::new(format, &mut sink);
+        let ctx = RenderContext::with_ancestors(format, 1, "    ", 0, &[]);
+        code.emit(&ctx, &mut emitter);
+        emitter.finish().unwrap();
+        out
+    }
+
+    /// Run with multiple threads, since nothing is emitted in parallel with one thread
+    fn in_pool(f: impl FnOnce() + Send) {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        pool.install(f);
+    }
+
+    fn section(i: usize) -> Code {
+        match i % 7 {
+            0 => cblock!(format!("fn f{i}() {{"), ["a;", "b;"], "}").into(),
+            1 => Code::from("else {}").connected(),
+            2 => Code::hard_line(format!("// {i}")),
+            3 => cconcat!["", "  ", format!("x{i};")].into(),
+            4 => cblock!("{", [], "}").connect_with(Connect::Tight).into(),
+            5 => clist!("," => ["a", "b"]).into(),
+            _ => cconcat!["", Code::from(format!("y{i}")), ""].into(),
+        }
+    }
+
+    #[test]
+    fn same_output() {
+        in_pool(|| {
+            let formats = [
+                Format::default(),
+                Format::indent(2).set_max_blank_lines(1),
+                Format::default().set_max_blank_lines(0),
+                Format::default().set_trim_blank_lines(true),
+            ];
+            for blank_lines in [0, 1, 3] {
+                let code: Code = Concat::separated(blank_lines, (0..1000).map(section)).into();
+                for format in &formats {
+                    let Code::Concat(concat) = &code else {
+                        unreachable!()
+                    };
+                    let mut lines = LineBuffer::default();
+                    let mut emitter = Emitter::new(format, &mut lines);
+                    assert!(emit_concat(
+                        concat,
+                        &RenderContext::new(format),
+                        &mut emitter
+                    ));
+                    emitter.finish().unwrap();
+
+                    let expected = sequential(&code, format);
+                    assert_eq!(expected, lines.iter().collect::<Vec<_>>().join("\n"));
+                    let before = EMITTED.with(|count| count.get());
+                    assert_eq!(expected, code.format_with(format));
+                    assert_eq!(before + 1, EMITTED.with(|count| count.get()));
+                    assert_eq!(expected, code.format_vec_with(format).join("\n"));
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn connected_first() {
+        in_pool(|| {
+            let mut body = vec![Code::from("a").connect_with(Connect::Join("; ".into()))];
+            body.extend((0..200).map(|i| Code::from(format!("b{i}")).connected()));
+            body.extend((0..200).map(section));
+            let code = Concat::new(body);
+            let format = Format::default();
+            assert_eq!(sequential(&code.clone().into(), &format), code.format());
+            assert!(code.format().starts_with("a b0 b1"));
+        });
+    }
+
+    #[test]
+    fn last_line_state() {
+        in_pool(|| {
+            let mut body: Vec<Code> = (0..200).map(section).collect();
+            // the hard break at the end of the block is kept when replayed
+            body.push(
+                cblock!("if x:", [Code::hard_line("# c")], "")
+                    .without_end()
+                    .into(),
+            );
+            let code = Concat::new(body);
+            let format = Format::default();
+
+            let mut out = String::new();
+            let mut sink = crate::TextSink::new(&mut out);
+            let mut emitter = Emitter::new(&format, &mut sink);
+            assert!(emit_concat(
+                &code,
+                &RenderContext::new(&format),
+                &mut emitter
+            ));
+            emitter.connect(&Connect::Space);
+            emitter.push_text("y");
+            emitter.finish().unwrap();
+
+            let expected = sequential(&cconcat![code, Code::from("y").connected()].into(), &format);
+            assert!(expected.ends_with("    # c\ny"));
+            assert_eq!(expected, out);
+        });
+    }
+
+    #[test]
+    fn lazy_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        in_pool(|| {
+            let count = Arc::new(AtomicUsize::new(0));
+            let lazy = crate::Lazy::new({
+                let count = Arc::clone(&count);
+                move || {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    "a();"
+                }
+            });
+            let code = Concat::new(
+                (0..5000).map(|i| cblock!(format!("fn f{i}() {{"), [lazy.clone()], "}")),
+            );
+            let expected = sequential(&code.clone().into(), &Format::default());
+            count.store(0, Ordering::SeqCst);
+            assert_eq!(expected, code.format());
+            assert_eq!(1, count.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn split() {
+        assert!(starts_on_new_line(&cblock!("{", [], "}").into()));
+        assert!(starts_on_new_line(&cconcat![Concat::empty(), "a"].into()));
+        assert!(!starts_on_new_line(
+            &Concat::new([Code::from("a").connected()]).into()
+        ));
+        assert!(!starts_on_new_line(
+            &cblock!("{", [], "}").connected().into()
+        ));
+        assert!(starts_on_new_line(
+            &Code::from("a").connect_with(Connect::NewLine)
+        ));
+    }
+}
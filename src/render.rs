@@ -0,0 +1,556 @@
+//! Emitting code with an explicit stack, so deeply nested code does not overflow the call stack
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+#[cfg(feature = "std")]
+use crate::cache::CacheRun;
+#[cfg(feature = "std")]
+use crate::emit::LineBuffer;
+use crate::inline::Summaries;
+use crate::tree::{
+    connect_mode, ends_with_hard_break, is_empty, is_item, CodeRef, CodeTree, Node, Tree,
+};
+use crate::{Block, Concat, Connect, Emitter, Format, List, NodeRef, RenderContext, Trailing};
+
+/// Stand-in for the render cache, which requires `std`
+#[cfg(not(feature = "std"))]
+pub(crate) enum CacheRun {}
+
+/// Remaining work for a code being emitted
+enum Frame<'a, I> {
+    /// Emit the code
+    Code(I),
+    /// Emit the rest of the block, starting from the body item at `next`
+    Block {
+        id: I,
+        block: &'a Block,
+        inline: bool,
+        next: usize,
+    },
+    /// Emit the rest of the concat, starting from the section at `next`
+    Concat {
+        id: I,
+        concat: &'a Concat,
+        next: usize,
+        first: bool,
+    },
+    /// Emit the rest of the list
+    List(ListFrame<'a, I>),
+}
+
+struct ListFrame<'a, I> {
+    id: I,
+    list: &'a List,
+    inline: bool,
+    /// Index of the last item that is not a hard line
+    last_item: Option<usize>,
+    /// Line count before the list
+    initial_count: usize,
+    /// Index of the next item to emit
+    next: usize,
+    /// If something is emitted before
+    first: bool,
+    /// Should next item be connected to the previous one
+    previous_allow_connect: bool,
+    /// The item just emitted and the line count before it, to add the separator
+    emitted: Option<(usize, usize)>,
+}
+
+/// Run the function as one render, where lazy code is only built once. See [`crate::Lazy`]
+///
+/// Renders started inside the function are part of the same render
+#[inline]
+pub(crate) fn scoped<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "std")]
+    {
+        crate::lazy::RenderScope::default().run(f)
+    }
+    #[cfg(not(feature = "std"))]
+    {
+        f()
+    }
+}
+
+/// Emit the code in the context
+pub(crate) fn emit(root: CodeRef<'_>, ctx: &RenderContext<'_>, emitter: &mut Emitter<'_>) {
+    scoped(|| {
+        #[cfg(feature = "rayon")]
+        if let Node::Concat(concat) = CodeTree.node(root) {
+            if crate::parallel::emit_concat(concat, ctx, emitter) {
+                return;
+            }
+        }
+        emit_with(CodeTree, root, ctx, emitter, None)
+    })
+}
+
+/// Emit the node of the tree in the context, reusing the output of unchanged blocks from the cache
+pub(crate) fn emit_with<'a, T: Tree<'a>>(
+    tree: T,
+    root: T::Id,
+    ctx: &RenderContext<'a>,
+    emitter: &mut Emitter<'_>,
+    cache: Option<&mut CacheRun>,
+) {
+    scoped(|| {
+        let mut ancestors: Vec<NodeRef<'_>> = ctx.ancestors().collect();
+        ancestors.reverse();
+        let mut renderer = Renderer {
+            tree,
+            format: ctx.format(),
+            depth: ctx.depth(),
+            indent: emitter.indent_text().into(),
+            ancestors,
+            stack: alloc::vec![Frame::Code(root)],
+            summaries: Summaries::default(),
+            cache,
+        };
+        renderer.run(emitter);
+    })
+}
+
+/// Emitting that can be paused between steps, for writing to async writers
+#[cfg(feature = "tokio")]
+pub(crate) struct Steps<'a>(Renderer<'a, 'static, CodeTree>, crate::lazy::RenderScope);
+
+#[cfg(feature = "tokio")]
+impl<'a> Steps<'a> {
+    pub(crate) fn new(code: &'a crate::Code, format: &'a Format) -> Self {
+        Self(
+            Renderer {
+                tree: CodeTree,
+                format,
+                depth: 0,
+                indent: String::new(),
+                ancestors: Vec::new(),
+                stack: alloc::vec![Frame::Code(CodeRef::Code(code))],
+                summaries: Summaries::default(),
+                cache: None,
+            },
+            crate::lazy::RenderScope::default(),
+        )
+    }
+
+    /// Emit the next part of the code. Returns false if everything is emitted
+    #[inline]
+    pub(crate) fn step(&mut self, emitter: &mut Emitter<'_>) -> bool {
+        // the steps are one render, even if they run on different threads
+        self.1.run(|| self.0.step(emitter))
+    }
+}
+
+struct Renderer<'a, 'c, T: Tree<'a>> {
+    tree: T,
+    format: &'a Format,
+    depth: usize,
+    /// Indentation of new lines, the same as in the emitter
+    indent: String,
+    /// Containers of the code being emitted, from the root
+    ancestors: Vec<NodeRef<'a>>,
+    stack: Vec<Frame<'a, T::Id>>,
+    /// Inline decisions that do not depend on the context, kept for the render
+    summaries: Summaries,
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    cache: Option<&'c mut CacheRun>,
+}
+
+impl<'a, T: Tree<'a>> Renderer<'a, '_, T> {
+    fn run(&mut self, emitter: &mut Emitter<'_>) {
+        while self.step(emitter) {}
+    }
+
+    /// Emit the next part of the code. Returns false if everything is emitted
+    fn step(&mut self, emitter: &mut Emitter<'_>) -> bool {
+        let Some(frame) = self.stack.pop() else {
+            return false;
+        };
+        match frame {
+            Frame::Code(id) => self.emit_code(id, emitter),
+            Frame::Block {
+                id,
+                block,
+                inline,
+                next,
+            } => self.step_block(id, block, inline, next, emitter),
+            Frame::Concat {
+                id,
+                concat,
+                next,
+                first,
+            } => self.step_concat(id, concat, next, first, emitter),
+            Frame::List(frame) => self.step_list(frame, emitter),
+        }
+        true
+    }
+
+    /// Get the context of the code being emitted, starting at the column
+    fn ctx(&self, column: usize) -> RenderContext<'_> {
+        RenderContext::with_ancestors(
+            self.format,
+            self.depth,
+            &self.indent,
+            column,
+            &self.ancestors,
+        )
+    }
+
+    /// Should the node be displayed in one line, when starting at the column
+    fn should_inline(&mut self, id: T::Id, column: usize) -> bool {
+        let ctx = RenderContext::with_ancestors(
+            self.format,
+            self.depth,
+            &self.indent,
+            column,
+            &self.ancestors,
+        );
+        crate::inline::should_inline(self.tree, id, &ctx, &mut self.summaries)
+    }
+
+    fn emit_code(&mut self, id: T::Id, emitter: &mut Emitter<'_>) {
+        match self.tree.node(id) {
+            Node::Line(line) => emitter.push_text(line),
+            Node::HardLine(line) => emitter.push_hard_line(line),
+            Node::Block(block) => self.enter_block(id, block, emitter),
+            Node::Concat(concat) => self.enter_concat(id, concat),
+            Node::List(list) => self.enter_list(id, list, emitter),
+            Node::Connected(mode) => {
+                mode.apply(emitter);
+                self.stack.push(Frame::Code(self.tree.child(id, 0)));
+            }
+            Node::Custom(body) => body.emit(&self.ctx(emitter.column()), emitter),
+        }
+    }
+
+    fn enter_block(&mut self, id: T::Id, block: &'a Block, emitter: &mut Emitter<'_>) {
+        #[cfg(feature = "std")]
+        if self.cache.is_some() && self.emit_cached(id, block, emitter) {
+            return;
+        }
+        self.enter_block_uncached(id, block, emitter);
+    }
+
+    /// Emit the block from the cache, if the output does not depend on what is before it.
+    /// Returns false if the block is not emitted
+    #[cfg(feature = "std")]
+    fn emit_cached(&mut self, id: T::Id, block: &'a Block, emitter: &mut Emitter<'_>) -> bool {
+        if self.format.minify || block.connect.is_connected() || !emitter.at_new_line() {
+            return false;
+        }
+        let Some(cache) = self.cache.as_deref_mut() else {
+            return false;
+        };
+        let Some(key) = cache.key(block, self.depth) else {
+            return false;
+        };
+        if let Some(entry) = cache.get(&key, block) {
+            emitter.replay(&entry.lines, &entry.tail);
+            return true;
+        }
+        // emit the block separately, without the indentation of the parents
+        let mut lines = LineBuffer::default();
+        let mut block_emitter = Emitter::new(self.format, &mut lines);
+        let mut renderer = Renderer {
+            tree: self.tree,
+            format: self.format,
+            depth: self.depth,
+            indent: String::new(),
+            ancestors: self.ancestors.clone(),
+            stack: Vec::new(),
+            summaries: core::mem::take(&mut self.summaries),
+            cache: Some(cache),
+        };
+        renderer.enter_block_uncached(id, block, &mut block_emitter);
+        renderer.run(&mut block_emitter);
+        // the last line is kept open, since text can be connected to it
+        let tail = block_emitter.into_tail();
+        emitter.replay(&lines, &tail);
+        self.summaries = renderer.summaries;
+        if let Some(cache) = renderer.cache {
+            cache.insert(key, block, lines, tail);
+        }
+        true
+    }
+
+    fn enter_block_uncached(&mut self, id: T::Id, block: &'a Block, emitter: &mut Emitter<'_>) {
+        block.connect.apply(emitter);
+        let column = emitter.column();
+        emitter.push_text(&block.start);
+        // when minifying, inline regardless of hard breaks.
+        // The hard lines will still end their lines
+        let inline = self.format.minify || self.should_inline(id, column);
+        self.ancestors.push(NodeRef::Block(block));
+        if !inline {
+            emitter.indent();
+            // only the added indentation is copied
+            let indent = emitter.indent_text();
+            self.indent.push_str(&indent[self.indent.len()..]);
+            self.depth += 1;
+        }
+        self.stack.push(Frame::Block {
+            id,
+            block,
+            inline,
+            next: 0,
+        });
+    }
+
+    fn step_block(
+        &mut self,
+        id: T::Id,
+        block: &'a Block,
+        inline: bool,
+        next: usize,
+        emitter: &mut Emitter<'_>,
+    ) {
+        if next < self.tree.len(id) {
+            self.stack.push(Frame::Block {
+                id,
+                block,
+                inline,
+                next: next + 1,
+            });
+            if inline {
+                emitter.connect(&Connect::Space);
+            } else {
+                emitter.begin_line();
+            }
+            self.stack.push(Frame::Code(self.tree.child(id, next)));
+            return;
+        }
+        self.ancestors.pop();
+        if !inline {
+            emitter.dedent();
+            self.indent.truncate(emitter.indent_text().len());
+            self.depth -= 1;
+        }
+        if block.no_end {
+            emitter.begin_line();
+            return;
+        }
+        if inline {
+            emitter.connect(&Connect::Space);
+        } else {
+            emitter.begin_line();
+        }
+        emitter.push_text(&block.end);
+    }
+
+    fn enter_concat(&mut self, id: T::Id, concat: &'a Concat) {
+        self.ancestors.push(NodeRef::Concat(concat));
+        self.stack.push(Frame::Concat {
+            id,
+            concat,
+            next: 0,
+            first: true,
+        });
+    }
+
+    /// Find the next non-empty child of the node, starting from the index
+    fn next_non_empty(&self, id: T::Id, next: usize) -> Option<(usize, T::Id)> {
+        let tree = self.tree;
+        (next..tree.len(id))
+            .map(|i| (i, tree.child(id, i)))
+            .find(|(_, c)| !is_empty(tree, *c))
+    }
+
+    fn step_concat(
+        &mut self,
+        id: T::Id,
+        concat: &'a Concat,
+        next: usize,
+        first: bool,
+        emitter: &mut Emitter<'_>,
+    ) {
+        let Some((i, code)) = self.next_non_empty(id, next) else {
+            self.ancestors.pop();
+            return;
+        };
+        if !first {
+            if self.format.minify {
+                emitter.connect(&Connect::Space);
+            } else {
+                if !connect_mode(self.tree, code).is_connected() {
+                    for _ in 0..concat.get_blank_lines() {
+                        emitter.begin_line();
+                        emitter.push_text("");
+                    }
+                }
+                emitter.begin_line();
+            }
+        }
+        self.stack.push(Frame::Concat {
+            id,
+            concat,
+            next: i + 1,
+            first: false,
+        });
+        self.stack.push(Frame::Code(code));
+    }
+
+    fn enter_list(&mut self, id: T::Id, list: &'a List, emitter: &mut Emitter<'_>) {
+        let tree = self.tree;
+        let column = emitter.column();
+        let inline = self.format.minify || self.should_inline(id, column);
+        self.ancestors.push(NodeRef::List(list));
+        self.stack.push(Frame::List(ListFrame {
+            id,
+            list,
+            inline,
+            last_item: tree.children(id).rposition(|c| is_item(tree, c)),
+            initial_count: emitter.line_count(),
+            next: 0,
+            first: true,
+            previous_allow_connect: false,
+            emitted: None,
+        }));
+    }
+
+    fn step_list(&mut self, mut frame: ListFrame<'a, T::Id>, emitter: &mut Emitter<'_>) {
+        let tree = self.tree;
+        let (id, list) = (frame.id, frame.list);
+        if let Some((i, previous_count)) = frame.emitted.take() {
+            let code = tree.child(id, i);
+            // check if next item can be connected
+            // only connect if the current is multi-line, and does not end with a hard break
+            frame.previous_allow_connect =
+                emitter.line_count() > previous_count + 1 && !ends_with_hard_break(tree, code);
+
+            let should_separate = match Some(i).cmp(&frame.last_item) {
+                Ordering::Less => is_item(tree, code),
+                Ordering::Equal => match list.trailing {
+                    Trailing::IfMultiLine => {
+                        // the comments after the last item are on their own lines
+                        let multi_line = emitter.line_count() > frame.initial_count + 1
+                            || self.next_non_empty(id, i + 1).is_some();
+                        !self.format.minify && multi_line
+                    }
+                    Trailing::Always => true,
+                    Trailing::Never => false,
+                },
+                Ordering::Greater => false,
+            };
+            if should_separate {
+                // the separator is put on a new line if the item ends with a hard break
+                emitter.connect(&Connect::Tight);
+                emitter.push_text(&list.separator);
+            }
+        }
+
+        let Some((i, code)) = self.next_non_empty(id, frame.next) else {
+            self.ancestors.pop();
+            return;
+        };
+        if !frame.first {
+            emitter.connect(&Connect::Space);
+            let connect = frame.inline
+                || (frame.previous_allow_connect && {
+                    // allow connect if the item is not block, or is non-inline block
+                    match tree.node(code) {
+                        Node::Block(_) => !self.should_inline(code, emitter.column()),
+                        _ => true,
+                    }
+                });
+            if !connect {
+                emitter.begin_line();
+            }
+        }
+        frame.first = false;
+        frame.next = i + 1;
+        frame.emitted = Some((i, emitter.line_count()));
+        self.stack.push(Frame::List(frame));
+        self.stack.push(Frame::Code(code));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::hash::{Hash, Hasher};
+    use std::collections::hash_map::DefaultHasher;
+
+    use crate::{cblock, cconcat, clist, Code, FormatCode, InlinePolicy};
+
+    use super::*;
+
+    fn nested(depth: usize) -> Code {
+        let mut code = Code::from("x");
+        for i in 0..depth {
+            code = match i % 4 {
+                0 => cblock!("{", ["a;", code], "}").into(),
+                1 => clist!("," => ["a", code]).into(),
+                2 => cconcat!["a", code.connected()].into(),
+                _ => cconcat!(1 => ["a", code]).into(),
+            };
+        }
+        code
+    }
+
+    fn hash(code: &Code) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        code.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn deep() {
+        let depth = 100_000;
+        let code = nested(depth);
+        let format = Format::indent(0);
+        let lines = code.format_vec_with(&format);
+        assert!(code.size_hint() >= lines.len());
+        assert_eq!(depth / 4 * 6 + 1, lines.len());
+        assert_eq!(["a", "", "a a,", "{", "a;", "a"], lines[..6]);
+        assert_eq!("x", lines[depth / 4 * 5]);
+        assert_eq!("},", lines[lines.len() - 1]);
+        assert!(!code.has_hard_break());
+        assert!(!code.should_inline());
+
+        let cloned = code.clone();
+        assert_eq!(code, cloned);
+        assert_eq!(hash(&code), hash(&cloned));
+        assert_ne!(code, nested(depth - 1));
+        assert_ne!(code, nested(depth + 1));
+    }
+
+    #[test]
+    fn deep_policies() {
+        // each block is decided once, without visiting the chain below it again
+        let depth = 100_000;
+        let chain = |bottom: Code, policy: &InlinePolicy<Block>| {
+            let mut code = bottom;
+            for _ in 0..depth {
+                code = cblock!("(", [code], ")")
+                    .inline_policy(policy.clone())
+                    .into();
+            }
+            code
+        };
+        let format = Format::indent(0);
+        let cases = [
+            (InlinePolicy::Always, 1),
+            (InlinePolicy::Never, depth * 2 + 1),
+            (InlinePolicy::Intrinsic, depth * 2 + 1),
+            (InlinePolicy::MaxItems(1), 1),
+            (InlinePolicy::MaxWidth(10), (depth - 2) * 2 + 1),
+            (InlinePolicy::AllChildrenInline, 1),
+            (
+                InlinePolicy::custom(|_, ctx: &RenderContext<'_>| ctx.depth() % 2 == 1),
+                3,
+            ),
+        ];
+        for (policy, expected) in &cases {
+            let code = chain(Code::from("x"), policy);
+            let lines = code.format_vec_with(&format);
+            assert_eq!(*expected, lines.len());
+            assert_eq!(depth * 4 + 1, code.flat_width(&format));
+        }
+
+        let code = chain(
+            cblock!("(", ["x"], ")").inlined().into(),
+            &InlinePolicy::Intrinsic,
+        );
+        assert!(code.should_inline());
+        assert_eq!(1, code.format_vec_with(&format).len());
+    }
+}
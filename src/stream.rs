@@ -0,0 +1,595 @@
+use alloc::borrow::Cow;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use std::io;
+
+use crate::emit::EmitterState;
+use crate::{Connect, Emitter, Format, Sink, Trailing};
+
+/// Emits code directly to an [`io::Write`], without building a [`Code`](crate::Code) tree
+///
+/// Blocks and lists are opened with [`block`](Self::block) and [`list`](Self::list),
+/// which return a [`Scope`] guard. The scope is used like the stream to add the body,
+/// and the block end or the list separator is written when the guard is dropped.
+/// Only the current line is kept in memory, so the output can be arbitrarily large.
+///
+/// The output is the same as emitting the equivalent tree, where the stream itself is a
+/// [`Concat`](crate::Concat), [`line`](Self::line) is a [`Code::Line`](crate::Code::Line)
+/// and [`hard_line`](Self::hard_line) is a [`Code::HardLine`](crate::Code::HardLine).
+/// Since the rest of the code is not known yet, blocks and lists are never inlined
+/// (except when minifying), and empty lists are not skipped.
+///
+/// The writer is not buffered. Wrap it in an [`io::BufWriter`] when writing to a file.
+/// Like a buffered writer, the last line is written when the stream is dropped, but errors
+/// are ignored. Call [`finish`](Self::finish) to handle them
+///
+/// # Examples
+/// ```
+/// use codize::{cblock, clist, Code, CodeStream, Connect, Format};
+///
+/// let mut stream = CodeStream::new(Vec::new(), Format::default());
+/// {
+///     let mut f = stream.block("fn main() {", "}");
+///     f.line("let x = [");
+///     {
+///         let mut list = f.list(",");
+///         list.line("1");
+///         list.hard_line("// two");
+///         list.line("2");
+///     }
+///     f.line_with(Connect::Tight, "];");
+/// }
+/// let out = stream.finish().unwrap();
+///
+/// let expected = cblock!("fn main() {", [
+///     "let x = [",
+///     clist!("," => ["1", Code::hard_line("// two"), "2"]),
+///     Code::from("];").connect_with(Connect::Tight),
+/// ], "}");
+/// assert_eq!(expected.to_string().as_bytes(), out);
+/// ```
+pub struct CodeStream<W: io::Write> {
+    output: Output<W>,
+    /// The open blocks and lists, from the outermost
+    scopes: Vec<Open>,
+    /// If something is emitted in the stream itself
+    started: bool,
+}
+
+/// Guard of a block or list opened in a [`CodeStream`], which closes it when dropped
+///
+/// The body is added through the guard, which dereferences to the stream
+pub struct Scope<'s, W: io::Write> {
+    stream: &'s mut CodeStream<W>,
+}
+
+/// A block or list that is not closed
+enum Open {
+    Block { end: Cow<'static, str> },
+    List(OpenList),
+}
+
+struct OpenList {
+    separator: Cow<'static, str>,
+    trailing: Trailing,
+    /// Line count when the list is opened
+    initial_count: usize,
+    /// If something is emitted in the list
+    started: bool,
+    /// Line count before the current element
+    element_count: usize,
+    /// Should the next item be connected to the previous one
+    previous_allow_connect: bool,
+    /// If the last item is emitted, and its separator is not decided yet
+    pending_item: bool,
+    /// Hard lines after the pending item. They are emitted after its separator
+    comments: Vec<String>,
+}
+
+/// The emitter of a stream, which is paused between calls
+struct Output<W> {
+    format: Format,
+    sink: IoSink<W>,
+    /// The state of the emitter, which is only taken while emitting
+    state: Option<EmitterState>,
+}
+
+impl<W: io::Write> CodeStream<W> {
+    /// Create a stream that writes to the output with the format
+    pub fn new(out: W, format: Format) -> Self {
+        let mut sink = IoSink {
+            out: Some(out),
+            started: false,
+            error: None,
+        };
+        let state = Emitter::new(&format, &mut sink).pause();
+        Self {
+            output: Output {
+                format,
+                sink,
+                state: Some(state),
+            },
+            scopes: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// Add a line of text
+    pub fn line(&mut self, text: impl AsRef<str>) {
+        self.line_with(Connect::Auto, text);
+    }
+
+    /// Add a line of text that is connected to the previous line. See [`Connect`]
+    pub fn line_with(&mut self, connect: Connect, text: impl AsRef<str>) {
+        self.begin_item();
+        self.output.emit(|emitter| {
+            connect.apply(emitter);
+            emitter.push_text(text.as_ref());
+        });
+        self.end_item();
+    }
+
+    /// Add a line of text that nothing can be connected after, such as a line comment.
+    /// In a list, hard lines are not items
+    pub fn hard_line(&mut self, text: impl AsRef<str>) {
+        let text = text.as_ref();
+        match self.scopes.last_mut() {
+            Some(Open::List(list)) if list.pending_item => {
+                // the separator of the item before goes before the comment
+                list.comments.push(text.to_string());
+            }
+            Some(Open::List(list)) => self.output.comment(list, text),
+            _ => {
+                self.begin_section();
+                self.output.hard_line(text);
+            }
+        }
+    }
+
+    /// Open a block. The body is added through the returned guard,
+    /// and `end` is added when the guard is dropped
+    pub fn block<TStart, TEnd>(&mut self, start: TStart, end: TEnd) -> Scope<'_, W>
+    where
+        TStart: Into<Cow<'static, str>>,
+        TEnd: Into<Cow<'static, str>>,
+    {
+        self.block_with(Connect::Auto, start, end)
+    }
+
+    /// Open a block that is connected to the previous line. See [`block`](Self::block)
+    pub fn block_with<TStart, TEnd>(
+        &mut self,
+        connect: Connect,
+        start: TStart,
+        end: TEnd,
+    ) -> Scope<'_, W>
+    where
+        TStart: Into<Cow<'static, str>>,
+        TEnd: Into<Cow<'static, str>>,
+    {
+        let start = start.into();
+        self.begin_item();
+        let minify = self.output.format.minify;
+        self.output.emit(|emitter| {
+            connect.apply(emitter);
+            emitter.push_text(&start);
+            if !minify {
+                emitter.indent();
+            }
+        });
+        self.scopes.push(Open::Block { end: end.into() });
+        Scope { stream: self }
+    }
+
+    /// Open a list with the separator, with a trailing separator if it is on multiple lines.
+    /// The items are added through the returned guard
+    pub fn list<TSep: Into<Cow<'static, str>>>(&mut self, separator: TSep) -> Scope<'_, W> {
+        self.list_with(separator, Trailing::IfMultiLine)
+    }
+
+    /// Open a list with the separator and the trailing separator option.
+    /// See [`list`](Self::list)
+    pub fn list_with<TSep: Into<Cow<'static, str>>>(
+        &mut self,
+        separator: TSep,
+        trailing: Trailing,
+    ) -> Scope<'_, W> {
+        self.begin_item();
+        let initial_count = self.output.emit(|emitter| emitter.line_count());
+        self.scopes.push(Open::List(OpenList {
+            separator: separator.into(),
+            trailing,
+            initial_count,
+            started: false,
+            element_count: initial_count,
+            previous_allow_connect: false,
+            pending_item: false,
+            comments: Vec::new(),
+        }));
+        Scope { stream: self }
+    }
+
+    /// Write the last line and flush the output, and get the output back.
+    ///
+    /// Returns the first error from writing
+    pub fn finish(mut self) -> io::Result<W> {
+        self.output.finish()?;
+        Ok(self.output.sink.out.take().expect("output"))
+    }
+
+    /// Start an item in the current scope
+    fn begin_item(&mut self) {
+        let Some(Open::List(list)) = self.scopes.last_mut() else {
+            self.begin_section();
+            return;
+        };
+        if list.pending_item {
+            list.pending_item = false;
+            self.output.separate(list);
+            for comment in core::mem::take(&mut list.comments) {
+                self.output.comment(list, &comment);
+            }
+        }
+        self.output.begin_element(list);
+    }
+
+    /// Finish an item in the current scope
+    fn end_item(&mut self) {
+        let Some(Open::List(list)) = self.scopes.last_mut() else {
+            return;
+        };
+        let (count, at_new_line) = self
+            .output
+            .emit(|emitter| (emitter.line_count(), emitter.at_new_line()));
+        // only connect if the item is multi-line, and does not end with a hard break
+        list.previous_allow_connect = count > list.element_count + 1 && !at_new_line;
+        list.pending_item = true;
+    }
+
+    /// Start the next code in the stream or in a block, the same way as [`crate::render`]
+    fn begin_section(&mut self) {
+        // the body of a block always starts on a new line
+        if self.scopes.is_empty() && !core::mem::replace(&mut self.started, true) {
+            return;
+        }
+        let minify = self.output.format.minify;
+        self.output.emit(|emitter| {
+            if minify {
+                emitter.connect(&Connect::Space);
+            } else {
+                emitter.begin_line();
+            }
+        });
+    }
+
+    /// Close the innermost block or list
+    fn close(&mut self) {
+        let minify = self.output.format.minify;
+        match self.scopes.pop() {
+            Some(Open::Block { end }) => self.output.emit(|emitter| {
+                if minify {
+                    emitter.connect(&Connect::Space);
+                } else {
+                    emitter.dedent();
+                    emitter.begin_line();
+                }
+                emitter.push_text(&end);
+            }),
+            Some(Open::List(mut list)) => {
+                if list.pending_item {
+                    let count = self.output.emit(|emitter| emitter.line_count());
+                    let should_separate = match list.trailing {
+                        Trailing::IfMultiLine => {
+                            // the comments after the last item are on their own lines
+                            let multi_line =
+                                count > list.initial_count + 1 || !list.comments.is_empty();
+                            !minify && multi_line
+                        }
+                        Trailing::Always => true,
+                        Trailing::Never => false,
+                    };
+                    if should_separate {
+                        self.output.separate(&list);
+                    }
+                }
+                for comment in core::mem::take(&mut list.comments) {
+                    self.output.comment(&mut list, &comment);
+                }
+            }
+            None => return,
+        }
+        self.end_item();
+    }
+}
+
+impl<W: io::Write> Output<W> {
+    fn emit<T>(&mut self, f: impl FnOnce(&mut Emitter<'_>) -> T) -> T {
+        let state = self.state.take().expect("emitter state");
+        let mut emitter = Emitter::resume(&self.format, &mut self.sink, state);
+        let result = f(&mut emitter);
+        self.state = Some(emitter.pause());
+        result
+    }
+
+    /// Write the last line and flush the output, if it is not finished yet
+    fn finish(&mut self) -> io::Result<()> {
+        let Some(state) = self.state.take() else {
+            return Ok(());
+        };
+        // errors are stored in the sink
+        let _ = Emitter::resume(&self.format, &mut self.sink, state).finish();
+        if let Some(error) = self.sink.error.take() {
+            return Err(error);
+        }
+        self.sink.out_mut().flush()
+    }
+
+    /// Start the next element in the list, the same way as [`crate::render`]
+    fn begin_element(&mut self, list: &mut OpenList) {
+        let started = core::mem::replace(&mut list.started, true);
+        let connect = self.format.minify || list.previous_allow_connect;
+        list.element_count = self.emit(|emitter| {
+            if started {
+                emitter.connect(&Connect::Space);
+                if !connect {
+                    emitter.begin_line();
+                }
+            }
+            emitter.line_count()
+        });
+    }
+
+    /// Add the separator after the last item in the list
+    fn separate(&mut self, list: &OpenList) {
+        self.emit(|emitter| {
+            emitter.connect(&Connect::Tight);
+            emitter.push_text(&list.separator);
+        });
+    }
+
+    /// Add a hard line in the list, which is not an item
+    fn comment(&mut self, list: &mut OpenList, text: &str) {
+        self.begin_element(list);
+        self.hard_line(text);
+        list.previous_allow_connect = false;
+    }
+
+    fn hard_line(&mut self, text: &str) {
+        self.emit(|emitter| emitter.push_hard_line(text));
+    }
+}
+
+impl<W: io::Write> Drop for CodeStream<W> {
+    fn drop(&mut self) {
+        // errors are only returned from `finish`
+        let _ = self.output.finish();
+    }
+}
+
+impl<W: io::Write> Deref for Scope<'_, W> {
+    type Target = CodeStream<W>;
+
+    fn deref(&self) -> &Self::Target {
+        self.stream
+    }
+}
+
+impl<W: io::Write> DerefMut for Scope<'_, W> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.stream
+    }
+}
+
+impl<W: io::Write> Drop for Scope<'_, W> {
+    fn drop(&mut self) {
+        self.stream.close();
+    }
+}
+
+/// Sink that writes the lines separated by `\n` to an [`io::Write`]
+struct IoSink<W> {
+    /// The output, which is taken when the stream is finished
+    out: Option<W>,
+    /// If a line is written before
+    started: bool,
+    /// The first error from writing
+    error: Option<io::Error>,
+}
+
+impl<W> IoSink<W> {
+    fn out_mut(&mut self) -> &mut W {
+        self.out.as_mut().expect("output")
+    }
+}
+
+impl<W: io::Write> Sink for IoSink<W> {
+    fn write_line(&mut self, line: &mut String) -> fmt::Result {
+        let started = self.started;
+        let out = self.out_mut();
+        let result = if started {
+            out.write_all(b"\n")
+                .and_then(|_| out.write_all(line.as_bytes()))
+        } else {
+            out.write_all(line.as_bytes())
+        };
+        self.started = true;
+        result.map_err(|error| {
+            self.error = Some(error);
+            fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use indoc::indoc;
+
+    use super::*;
+    use crate::{cblock, cconcat, clist, Code, FormatCode};
+
+    fn formats() -> [Format; 4] {
+        [
+            Format::default(),
+            Format::indent(2).set_max_blank_lines(0),
+            Format::default().set_trim_blank_lines(true),
+            Format::minify(),
+        ]
+    }
+
+    fn assert_same(code: impl Into<Code>, f: impl Fn(&mut CodeStream<Vec<u8>>)) {
+        let code = code.into();
+        for format in formats() {
+            let mut stream = CodeStream::new(Vec::new(), format.clone());
+            f(&mut stream);
+            let out = String::from_utf8(stream.finish().unwrap()).unwrap();
+            assert_eq!(code.format_with(&format), out);
+        }
+    }
+
+    #[test]
+    fn blocks() {
+        let code = cconcat![
+            "use x;",
+            "",
+            cblock!(
+                "fn main() {",
+                [
+                    cblock!("if x {", ["", "a();", ""], "}"),
+                    cblock!("else {", [Code::hard_line("// b")], "}").connected(),
+                    Code::from(";").connect_with(Connect::Tight),
+                    Code::hard_line("// c"),
+                    Code::from("d").connected(),
+                ],
+                "}"
+            ),
+        ];
+        assert_same(code, |s| {
+            s.line("use x;");
+            s.line("");
+            let mut f = s.block("fn main() {", "}");
+            {
+                let mut b = f.block("if x {", "}");
+                b.line("");
+                b.line("a();");
+                b.line("");
+            }
+            f.block_with(Connect::Space, "else {", "}")
+                .hard_line("// b");
+            f.line_with(Connect::Tight, ";");
+            f.hard_line("// c");
+            f.line_with(Connect::Space, "d");
+        });
+    }
+
+    #[test]
+    fn lists() {
+        let code = cblock!(
+            "f(",
+            [clist!("," => [
+                Code::hard_line("// first"),
+                "a",
+                Code::hard_line("// a"),
+                Code::hard_line("// a2"),
+                cblock!("{", ["b"], "}"),
+                "c",
+                cblock!("[", [clist!(";" => ["d", Code::hard_line("// d")])], "]"),
+                Code::hard_line("// last"),
+            ])],
+            ")"
+        );
+        assert_same(code, |s| {
+            let mut f = s.block("f(", ")");
+            let mut l = f.list(",");
+            l.hard_line("// first");
+            l.line("a");
+            l.hard_line("// a");
+            l.hard_line("// a2");
+            l.block("{", "}").line("b");
+            l.line("c");
+            {
+                let mut b = l.block("[", "]");
+                let mut l = b.list(";");
+                l.line("d");
+                l.hard_line("// d");
+            }
+            l.hard_line("// last");
+        });
+    }
+
+    #[test]
+    fn trailing() {
+        for trailing in [Trailing::IfMultiLine, Trailing::Always, Trailing::Never] {
+            let list = clist!("," => ["a", "b"]);
+            let list = match trailing {
+                Trailing::IfMultiLine => list,
+                Trailing::Always => list.always_trail(),
+                Trailing::Never => list.no_trail(),
+            };
+            let code = cconcat![cblock!("x = [", [list], "];"), clist!("," => ["c"]),];
+            assert_same(code, |s| {
+                {
+                    let mut b = s.block("x = [", "];");
+                    let mut l = b.list_with(",", trailing.clone());
+                    l.line("a");
+                    l.line("b");
+                }
+                s.list(",").line("c");
+            });
+        }
+    }
+
+    #[test]
+    fn output() {
+        let mut stream = CodeStream::new(Vec::new(), Format::default());
+        {
+            let mut f = stream.block("fn main() {", "}");
+            f.line("a();");
+            f.block_with(Connect::Space, "{", "}").line("b();");
+        }
+        stream.line("");
+        let expected = indoc! {"
+            fn main() {
+                a(); {
+                    b();
+                }
+            }
+        "};
+        assert_eq!(expected.as_bytes(), stream.finish().unwrap());
+    }
+
+    #[test]
+    fn drop_without_finish() {
+        let mut out = Vec::new();
+        {
+            let mut stream = CodeStream::new(&mut out, Format::default());
+            stream.line("a();");
+            stream.block("if x {", "}").line("b();");
+        }
+        let expected = indoc! {"
+            a();
+            if x {
+                b();
+            }"};
+        assert_eq!(expected.as_bytes(), out);
+    }
+
+    #[test]
+    fn error() {
+        struct Fail;
+        impl io::Write for Fail {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("fail"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut stream = CodeStream::new(Fail, Format::default());
+        stream.line("a");
+        stream.line("b");
+        let error = stream.finish().err().unwrap();
+        assert_eq!("fail", error.to_string());
+    }
+}
@@ -0,0 +1,566 @@
+//! Traversal of the code tree with an explicit stack, so deeply nested code
+//! does not overflow the call stack
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+
+use crate::{Block, Code, Concat, Connect, CustomCode, Format, List, NodeRef};
+
+/// Read access to the nodes of a code tree
+///
+/// This is implemented for [`Code`] by [`CodeTree`] and for the nodes of a
+/// [`CodeArena`](crate::CodeArena), so both are emitted and measured the same way
+pub(crate) trait Tree<'a>: Copy {
+    /// Handle to a node in the tree
+    type Id: Copy;
+
+    /// Get the node, without the children
+    fn node(self, id: Self::Id) -> Node<'a>;
+
+    /// Get the number of children of the node
+    fn len(self, id: Self::Id) -> usize;
+
+    /// Get a child of the node
+    ///
+    /// # Panics
+    /// If the index is out of bounds
+    fn child(self, id: Self::Id, index: usize) -> Self::Id;
+
+    /// Iterate over the children of the node
+    #[inline]
+    fn children(
+        self,
+        id: Self::Id,
+    ) -> impl DoubleEndedIterator<Item = Self::Id> + ExactSizeIterator + Clone {
+        (0..self.len(id)).map(move |i| self.child(id, i))
+    }
+}
+
+/// A node of a code tree without the children. See [`Tree`]
+///
+/// The body of blocks, lists and concats is empty for nodes of a
+/// [`CodeArena`](crate::CodeArena), and is read with [`Tree::children`] instead
+#[derive(Clone, Copy)]
+pub(crate) enum Node<'a> {
+    Line(&'a str),
+    HardLine(&'a str),
+    Block(&'a Block),
+    List(&'a List),
+    Concat(&'a Concat),
+    /// Code connected with the mode. The child is the code
+    Connected(&'a Connect),
+    /// Custom code. The box is borrowed, so custom codes of zero-sized types have different
+    /// addresses
+    #[allow(clippy::borrowed_box)]
+    Custom(&'a Box<dyn CustomCode>),
+}
+
+/// The [`Tree`] of [`Code`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CodeTree;
+
+/// Reference to a code in a [`CodeTree`], which can be a node that is not inside a [`Code`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum CodeRef<'a> {
+    Code(&'a Code),
+    Node(NodeRef<'a>),
+}
+
+impl CodeTree {
+    /// Get the node of the code, looking through [`Code::Shared`]
+    pub(crate) fn code_node(code: &Code) -> Node<'_> {
+        match code.unshared() {
+            Code::Line(line) => Node::Line(line),
+            Code::HardLine(line) => Node::HardLine(line),
+            Code::Block(block) => Node::Block(block),
+            Code::List(list) => Node::List(list),
+            Code::Concat(concat) => Node::Concat(concat),
+            Code::Connected(mode, _) => Node::Connected(mode),
+            Code::Custom(body) => Node::Custom(body),
+            Code::Shared(_) => unreachable!("shared code is unwrapped"),
+        }
+    }
+
+    /// Get the children of the code, looking through [`Code::Shared`]
+    #[inline]
+    pub(crate) fn code_children(code: &Code) -> &[Code] {
+        code.unshared().children()
+    }
+
+    fn body(id: CodeRef<'_>) -> &[Code] {
+        match id {
+            CodeRef::Code(code) => Self::code_children(code),
+            CodeRef::Node(NodeRef::Block(block)) => block.body(),
+            CodeRef::Node(NodeRef::List(list)) => list.body(),
+            CodeRef::Node(NodeRef::Concat(concat)) => concat,
+        }
+    }
+}
+
+impl<'a> From<&'a Code> for CodeRef<'a> {
+    #[inline]
+    fn from(code: &'a Code) -> Self {
+        CodeRef::Code(code)
+    }
+}
+
+impl<'a> From<NodeRef<'a>> for CodeRef<'a> {
+    #[inline]
+    fn from(node: NodeRef<'a>) -> Self {
+        CodeRef::Node(node)
+    }
+}
+
+impl<'a> Tree<'a> for CodeTree {
+    type Id = CodeRef<'a>;
+
+    fn node(self, id: CodeRef<'a>) -> Node<'a> {
+        match id {
+            CodeRef::Code(code) => Self::code_node(code),
+            CodeRef::Node(NodeRef::Block(block)) => Node::Block(block),
+            CodeRef::Node(NodeRef::List(list)) => Node::List(list),
+            CodeRef::Node(NodeRef::Concat(concat)) => Node::Concat(concat),
+        }
+    }
+
+    #[inline]
+    fn len(self, id: CodeRef<'a>) -> usize {
+        Self::body(id).len()
+    }
+
+    #[inline]
+    fn child(self, id: CodeRef<'a>, index: usize) -> CodeRef<'a> {
+        CodeRef::Code(&Self::body(id)[index])
+    }
+}
+
+impl Code {
+    /// Get the codes directly inside this code
+    ///
+    /// Custom code is opaque and has no children
+    pub(crate) fn children(&self) -> &[Code] {
+        match self {
+            Code::Block(block) => block.body(),
+            Code::List(list) => list.body(),
+            Code::Concat(concat) => concat,
+            Code::Connected(_, code) => core::slice::from_ref(&**code),
+            Code::Shared(code) => core::slice::from_ref(&**code),
+            _ => &[],
+        }
+    }
+
+    /// Get the code inside [`Code::Shared`]
+    pub(crate) fn unshared(&self) -> &Code {
+        let mut code = self;
+        while let Code::Shared(inner) = code {
+            code = inner;
+        }
+        code
+    }
+
+    /// Get the code inside [`Code::Connected`] and [`Code::Shared`]
+    pub(crate) fn unwrapped(&self) -> &Code {
+        let mut code = self;
+        loop {
+            code = match code {
+                Code::Connected(_, inner) => inner,
+                Code::Shared(inner) => inner,
+                _ => return code,
+            };
+        }
+    }
+
+    /// Move the children out of this code into `out`, leaving this code without children
+    fn take_children(&mut self, out: &mut Vec<Code>) {
+        match self {
+            Code::Block(block) => out.append(&mut block.concat_body.body),
+            Code::List(list) => out.append(&mut list.concat_body.body),
+            Code::Concat(concat) => out.append(&mut concat.body),
+            Code::Connected(_, code) => out.push(core::mem::replace(&mut **code, placeholder())),
+            // the shared code is only dropped with the last reference
+            Code::Shared(code) => {
+                if let Some(code) = Arc::get_mut(code) {
+                    out.push(core::mem::replace(code, placeholder()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Set the children of a code created by [`Code::shallow_clone`]
+    fn set_children(&mut self, children: Vec<Code>) {
+        match self {
+            Code::Block(block) => block.concat_body.body = children,
+            Code::List(list) => list.concat_body.body = children,
+            Code::Concat(concat) => concat.body = children,
+            Code::Connected(_, code) => {
+                if let Some(child) = children.into_iter().next() {
+                    **code = child;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Clone this code without the children
+    fn shallow_clone(&self) -> Code {
+        match self {
+            Code::Line(line) => Code::Line(line.clone()),
+            Code::HardLine(line) => Code::HardLine(line.clone()),
+            Code::Block(block) => Code::Block(Box::new(block.shallow_clone())),
+            Code::List(list) => Code::List(List {
+                concat_body: list.concat_body.shallow_clone(),
+                separator: list.separator.clone(),
+                trailing: list.trailing.clone(),
+                inline_policy: list.inline_policy.clone(),
+            }),
+            Code::Concat(concat) => Code::Concat(concat.shallow_clone()),
+            Code::Connected(mode, _) => Code::Connected(mode.clone(), Box::new(placeholder())),
+            Code::Custom(body) => Code::Custom(body.clone()),
+            Code::Shared(code) => Code::Shared(Arc::clone(code)),
+        }
+    }
+
+    /// Compare this code with another without the children, except for the number of children
+    fn shallow_eq(&self, other: &Code) -> bool {
+        match (self, other) {
+            (Code::Line(a), Code::Line(b)) | (Code::HardLine(a), Code::HardLine(b)) => a == b,
+            (Code::Block(a), Code::Block(b)) => {
+                a.shallow_eq(b) && a.concat_body.len() == b.concat_body.len()
+            }
+            (Code::List(a), Code::List(b)) => {
+                a.separator == b.separator
+                    && a.trailing == b.trailing
+                    && a.inline_policy == b.inline_policy
+                    && a.concat_body.shallow_eq(&b.concat_body)
+            }
+            (Code::Concat(a), Code::Concat(b)) => a.shallow_eq(b),
+            (Code::Connected(a, _), Code::Connected(b, _)) => a == b,
+            (Code::Custom(a), Code::Custom(b)) => a == b,
+            (Code::Shared(_), Code::Shared(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Hash this code without the children, except for the number of children
+    pub(crate) fn shallow_hash<H: Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
+        match self {
+            Code::Line(line) | Code::HardLine(line) => line.hash(state),
+            Code::Block(block) => {
+                block.connect.hash(state);
+                block.start.hash(state);
+                block.end.hash(state);
+                block.no_end.hash(state);
+                block.inline_policy.hash(state);
+                block.concat_body.shallow_hash(state);
+            }
+            Code::List(list) => {
+                list.separator.hash(state);
+                list.trailing.hash(state);
+                list.inline_policy.hash(state);
+                list.concat_body.shallow_hash(state);
+            }
+            Code::Concat(concat) => concat.shallow_hash(state),
+            Code::Connected(mode, _) => mode.hash(state),
+            Code::Custom(body) => body.hash(state),
+            Code::Shared(_) => {}
+        }
+    }
+}
+
+impl Block {
+    /// Clone this block without the body
+    pub(crate) fn shallow_clone(&self) -> Block {
+        Block {
+            connect: self.connect.clone(),
+            start: self.start.clone(),
+            end: self.end.clone(),
+            no_end: self.no_end,
+            concat_body: self.concat_body.shallow_clone(),
+            inline_policy: self.inline_policy.clone(),
+        }
+    }
+
+    /// Compare this block with another without the body, not even the number of children
+    pub(crate) fn shallow_eq(&self, other: &Block) -> bool {
+        self.connect == other.connect
+            && self.start == other.start
+            && self.end == other.end
+            && self.no_end == other.no_end
+            && self.inline_policy == other.inline_policy
+            && self.concat_body.blank_lines == other.concat_body.blank_lines
+    }
+}
+
+impl Concat {
+    fn shallow_clone(&self) -> Concat {
+        Concat::empty().blank_lines(self.blank_lines)
+    }
+
+    fn shallow_eq(&self, other: &Concat) -> bool {
+        self.blank_lines == other.blank_lines && self.body.len() == other.body.len()
+    }
+
+    fn shallow_hash<H: Hasher>(&self, state: &mut H) {
+        self.blank_lines.hash(state);
+        self.body.len().hash(state);
+    }
+}
+
+/// Code that takes the place of a moved child. This does not allocate
+#[inline]
+fn placeholder() -> Code {
+    Code::Line(Cow::Borrowed(""))
+}
+
+/// Iterate over the codes and all of their descendants, in pre-order
+pub(crate) fn descendants(codes: &[Code]) -> impl Iterator<Item = &Code> {
+    let mut stack = vec![codes.iter()];
+    core::iter::from_fn(move || loop {
+        let iter = stack.last_mut()?;
+        match iter.next() {
+            Some(code) => {
+                let children = code.children();
+                if !children.is_empty() {
+                    stack.push(children.iter());
+                }
+                return Some(code);
+            }
+            None => {
+                stack.pop();
+            }
+        }
+    })
+}
+
+/// Clone the codes
+pub(crate) fn clone_all(codes: &[Code]) -> Vec<Code> {
+    struct Frame<'a> {
+        iter: core::slice::Iter<'a, Code>,
+        out: Vec<Code>,
+        /// The cloned parent waiting for the children
+        parent: Option<Code>,
+    }
+    let mut stack = vec![Frame {
+        iter: codes.iter(),
+        out: Vec::with_capacity(codes.len()),
+        parent: None,
+    }];
+    loop {
+        let frame = stack.last_mut().expect("root frame");
+        if let Some(code) = frame.iter.next() {
+            // shared code is cloned by reference
+            let children = match code {
+                Code::Shared(_) => &[],
+                code => code.children(),
+            };
+            if children.is_empty() {
+                frame.out.push(code.shallow_clone());
+            } else {
+                stack.push(Frame {
+                    iter: children.iter(),
+                    out: Vec::with_capacity(children.len()),
+                    parent: Some(code.shallow_clone()),
+                });
+            }
+            continue;
+        }
+        let frame = stack.pop().expect("frame");
+        let Some(mut parent) = frame.parent else {
+            return frame.out;
+        };
+        parent.set_children(frame.out);
+        stack.last_mut().expect("root frame").out.push(parent);
+    }
+}
+
+/// Drop the codes
+pub(crate) fn drop_all(mut codes: Vec<Code>) {
+    while let Some(mut code) = codes.pop() {
+        code.take_children(&mut codes);
+    }
+}
+
+/// Compare the codes
+pub(crate) fn eq_all(a: &[Code], b: &[Code]) -> bool {
+    // the number of children is compared, so the traversals are aligned
+    a.len() == b.len()
+        && descendants(a)
+            .zip(descendants(b))
+            .all(|(a, b)| a.shallow_eq(b))
+}
+
+/// Hash the codes
+pub(crate) fn hash_all<H: Hasher>(codes: &[Code], state: &mut H) {
+    codes.len().hash(state);
+    for code in descendants(codes) {
+        code.shallow_hash(state);
+    }
+}
+
+/// Iterate over the node and all of its descendants in the tree, in pre-order
+pub(crate) fn descendants_in<'a, T: Tree<'a>>(tree: T, id: T::Id) -> impl Iterator<Item = T::Id> {
+    let mut stack = vec![id];
+    core::iter::from_fn(move || {
+        let id = stack.pop()?;
+        stack.extend(tree.children(id).rev());
+        Some(id)
+    })
+}
+
+/// See [`Code::is_empty`]
+pub(crate) fn is_empty<'a, T: Tree<'a>>(tree: T, mut id: T::Id) -> bool {
+    loop {
+        match tree.node(id) {
+            Node::Concat(_) | Node::List(_) => return tree.len(id) == 0,
+            Node::Connected(_) => id = tree.child(id, 0),
+            Node::Custom(body) => return body.is_empty(),
+            _ => return false,
+        }
+    }
+}
+
+/// See [`Code::has_hard_break`]
+pub(crate) fn has_hard_break<'a, T: Tree<'a>>(tree: T, id: T::Id) -> bool {
+    descendants_in(tree, id).any(|id| match tree.node(id) {
+        Node::HardLine(_) => true,
+        Node::Custom(body) => body.has_hard_break(),
+        _ => false,
+    })
+}
+
+/// See [`Code::ends_with_hard_break`]
+pub(crate) fn ends_with_hard_break<'a, T: Tree<'a>>(tree: T, mut id: T::Id) -> bool {
+    loop {
+        id = match tree.node(id) {
+            Node::HardLine(_) => return true,
+            Node::Concat(_) => match last_non_empty(tree, id) {
+                Some(last) => last,
+                None => return false,
+            },
+            // items ending with a hard break have the separator put on the next line,
+            // so only hard lines directly in the list end the list with a hard break
+            Node::List(_) => {
+                return last_non_empty(tree, id).is_some_and(|last| is_hard_line(tree, last))
+            }
+            Node::Connected(_) => tree.child(id, 0),
+            Node::Custom(body) => return body.ends_with_hard_break(),
+            _ => return false,
+        };
+    }
+}
+
+fn last_non_empty<'a, T: Tree<'a>>(tree: T, id: T::Id) -> Option<T::Id> {
+    tree.children(id).rev().find(|c| !is_empty(tree, *c))
+}
+
+#[inline]
+pub(crate) fn is_hard_line<'a, T: Tree<'a>>(tree: T, id: T::Id) -> bool {
+    matches!(tree.node(id), Node::HardLine(_))
+}
+
+/// Hard lines directly in a list are not items (for example, comments)
+#[inline]
+pub(crate) fn is_item<'a, T: Tree<'a>>(tree: T, id: T::Id) -> bool {
+    !is_empty(tree, id) && !is_hard_line(tree, id)
+}
+
+/// See [`Code::connect_mode`]
+pub(crate) fn connect_mode<'a, T: Tree<'a>>(tree: T, id: T::Id) -> &'a Connect {
+    static AUTO: Connect = Connect::Auto;
+    match tree.node(id) {
+        Node::Block(block) => &block.connect,
+        Node::Connected(mode) => mode,
+        _ => &AUTO,
+    }
+}
+
+/// Sum of the byte size hints of the codes at the depth.
+///
+/// Every line is assumed to start on a new line, since joining with a space
+/// does not take more bytes than a line break
+pub(crate) fn byte_size_hint(codes: &[Code], depth: usize, format: &Format) -> usize {
+    let mut total = 0;
+    let mut stack = vec![(codes.iter(), depth)];
+    while let Some((iter, depth)) = stack.last_mut() {
+        let depth = *depth;
+        let Some(code) = iter.next() else {
+            stack.pop();
+            continue;
+        };
+        total += match code {
+            Code::Line(line) | Code::HardLine(line) => line.len() + line_byte_size(depth, format),
+            Code::Block(block) => block.own_byte_size_hint(depth, format),
+            Code::List(list) => list.own_byte_size_hint(depth, format),
+            Code::Concat(concat) => concat.own_size_hint(),
+            Code::Connected(mode, _) => mode.byte_size_hint(),
+            Code::Shared(_) => 0,
+            Code::Custom(body) => {
+                body.byte_size_hint(format) + body.size_hint() * indent_byte_size(depth, format)
+            }
+        };
+        let children = code.children();
+        if !children.is_empty() {
+            let depth = if matches!(code, Code::Block(_)) {
+                depth + 1
+            } else {
+                depth
+            };
+            stack.push((children.iter(), depth));
+        }
+    }
+    total
+}
+
+/// Number of bytes of the indentation at the depth
+#[inline]
+pub(crate) fn indent_byte_size(depth: usize, format: &Format) -> usize {
+    match format.indent {
+        i if i < 0 => depth,
+        i => depth * i as usize,
+    }
+}
+
+/// Number of bytes of a line break and the indentation at the depth
+#[inline]
+pub(crate) fn line_byte_size(depth: usize, format: &Format) -> usize {
+    indent_byte_size(depth, format) + 1
+}
+
+/// See [`FormatCode::size_hint`](crate::FormatCode::size_hint)
+pub(crate) fn size_hint<'a, T: Tree<'a>>(tree: T, id: T::Id) -> usize {
+    descendants_in(tree, id)
+        .map(|id| match tree.node(id) {
+            Node::Line(_) | Node::HardLine(_) => 1,
+            Node::Block(block) => {
+                let end = if block.no_end { 1 } else { 2 };
+                end + blank_line_count(tree, id, block.concat_body.blank_lines)
+            }
+            Node::List(list) => {
+                // separators after items ending with a hard break are put on new lines
+                let separator_lines = tree
+                    .children(id)
+                    .filter(|c| !is_hard_line(tree, *c) && ends_with_hard_break(tree, *c))
+                    .count();
+                blank_line_count(tree, id, list.concat_body.blank_lines) + separator_lines
+            }
+            Node::Concat(concat) => blank_line_count(tree, id, concat.blank_lines),
+            Node::Connected(_) => 0,
+            Node::Custom(body) => body.size_hint(),
+        })
+        .sum()
+}
+
+/// Number of blank lines between the non-empty children
+fn blank_line_count<'a, T: Tree<'a>>(tree: T, id: T::Id, blank_lines: usize) -> usize {
+    if blank_lines == 0 {
+        return 0;
+    }
+    let separators = tree.children(id).filter(|c| !is_empty(tree, *c)).count();
+    separators.saturating_sub(1) * blank_lines
+}
@@ -0,0 +1,186 @@
+use alloc::sync::Arc;
+use core::hash::{Hash, Hasher};
+use core::ptr;
+
+use crate::inline::Summaries;
+use crate::tree::Tree;
+use crate::Format;
+
+/// Measure the display width of text
+///
+/// The width is used for layout decisions, such as whether a block fits on one line.
+/// Any `Fn(&str) -> usize` can also be used as a measurer.
+pub trait MeasureWidth: Send + Sync {
+    /// Get the number of columns the text occupies when displayed
+    fn width(&self, text: &str) -> usize;
+}
+
+impl<F> MeasureWidth for F
+where
+    F: Fn(&str) -> usize + Send + Sync,
+{
+    #[inline]
+    fn width(&self, text: &str) -> usize {
+        self(text)
+    }
+}
+
+/// Measure width by counting `char`s
+///
+/// This is the default when the `unicode-width` feature is disabled
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CharWidth;
+
+impl MeasureWidth for CharWidth {
+    #[inline]
+    fn width(&self, text: &str) -> usize {
+        text.chars().count()
+    }
+}
+
+/// Measure width according to the Unicode East Asian Width property,
+/// so CJK characters and emojis take 2 columns
+///
+/// This is the default when the `unicode-width` feature is enabled
+#[cfg(feature = "unicode-width")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UnicodeWidth;
+
+#[cfg(feature = "unicode-width")]
+impl MeasureWidth for UnicodeWidth {
+    #[inline]
+    fn width(&self, text: &str) -> usize {
+        unicode_width::UnicodeWidthStr::width(text)
+    }
+}
+
+/// The measurer used by a [`Format`](crate::Format)
+///
+/// Default measurers compare equal, and custom measurers are compared and hashed
+/// by their address
+#[derive(Clone, Default)]
+pub enum Measure {
+    /// [`UnicodeWidth`] if the `unicode-width` feature is enabled, otherwise [`CharWidth`]
+    #[default]
+    Default,
+    /// A custom measurer
+    Custom(Arc<dyn MeasureWidth>),
+}
+
+impl MeasureWidth for Measure {
+    #[inline]
+    fn width(&self, text: &str) -> usize {
+        match self {
+            #[cfg(feature = "unicode-width")]
+            Self::Default => UnicodeWidth.width(text),
+            #[cfg(not(feature = "unicode-width"))]
+            Self::Default => CharWidth.width(text),
+            Self::Custom(measure) => measure.width(text),
+        }
+    }
+}
+
+impl core::fmt::Debug for Measure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Default => f.write_str("Default"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl PartialEq for Measure {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Default, Self::Default) => true,
+            (Self::Custom(a), Self::Custom(b)) => ptr::addr_eq(Arc::as_ptr(a), Arc::as_ptr(b)),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Measure {}
+
+impl Hash for Measure {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
+        if let Self::Custom(measure) = self {
+            Arc::as_ptr(measure).cast::<()>().hash(state);
+        }
+    }
+}
+
+/// Helper for computing the width of code segments joined on one line
+///
+/// Mirrors how connected segments are joined when emitting: a space is added
+/// between segments if the line is not empty
+#[derive(Debug, Default)]
+pub(crate) struct FlatWidth {
+    width: usize,
+}
+
+impl FlatWidth {
+    /// Join a segment with a space
+    #[inline]
+    pub fn join(&mut self, width: usize) {
+        if self.width > 0 {
+            self.width += 1;
+        }
+        self.width += width;
+    }
+
+    /// Append a segment without a space
+    #[inline]
+    pub fn push(&mut self, width: usize) {
+        self.width += width;
+    }
+
+    /// Get the total width
+    #[inline]
+    pub fn get(&self) -> usize {
+        self.width
+    }
+}
+
+/// See [`FormatCode::flat_width`](crate::FormatCode::flat_width)
+pub(crate) fn flat_width<'a, T: Tree<'a>>(tree: T, id: T::Id, format: &Format) -> usize {
+    Summaries::default().get(tree, id, format).width
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn char_width() {
+        assert_eq!(CharWidth.width("hello"), 5);
+        assert_eq!(CharWidth.width("你好"), 2);
+    }
+
+    #[cfg(feature = "unicode-width")]
+    #[test]
+    fn unicode_width() {
+        assert_eq!(UnicodeWidth.width("hello"), 5);
+        assert_eq!(UnicodeWidth.width("你好"), 4);
+        assert_eq!(UnicodeWidth.width("\"🦀\""), 4);
+    }
+
+    #[test]
+    fn measure_eq() {
+        let custom = Measure::Custom(Arc::new(CharWidth));
+        assert_eq!(Measure::Default, Measure::default());
+        assert_eq!(custom, custom.clone());
+        assert_ne!(custom, Measure::Custom(Arc::new(CharWidth)));
+        assert_ne!(custom, Measure::Default);
+        #[cfg(feature = "unicode-width")]
+        assert_eq!(4, Measure::Default.width("你好"));
+        #[cfg(not(feature = "unicode-width"))]
+        assert_eq!(2, Measure::Default.width("你好"));
+    }
+
+    #[test]
+    fn closure() {
+        let measure = |s: &str| s.len();
+        assert_eq!(measure.width("你好"), 6);
+    }
+}
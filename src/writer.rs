@@ -0,0 +1,458 @@
+use alloc::borrow::Cow;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use crate::{Block, Code, Concat, List};
+
+/// Builds a [`Code`] tree from text written with [`write!`] and [`writeln!`]
+///
+/// Each line written becomes a [`Code::Line`] in the current scope.
+/// Blocks are opened with [`open_block`](Self::open_block) and closed with
+/// [`close_block`](Self::close_block), or with [`indent`](Self::indent) and
+/// [`dedent`](Self::dedent) for generators that write the start and end lines themselves.
+/// Lists are opened with [`list`](Self::list), where each line is an item.
+///
+/// The result is a tree of [`Block`], [`List`] and [`Concat`] nodes, so inline policies and
+/// the [`Format`](crate::Format) apply as if the tree is built with the macros.
+/// Lines are kept as-is, so they should not contain the indentation
+///
+/// # Examples
+/// ```
+/// use core::fmt::Write;
+/// use codize::{cblock, clist, Code, CodeWriter, Concat};
+///
+/// let mut w = CodeWriter::new();
+/// writeln!(w, "fn main() {{").unwrap();
+/// w.indent();
+/// write!(w, "let x = ").unwrap();
+/// writeln!(w, "[").unwrap();
+/// {
+///     let mut list = w.list(",");
+///     for i in 1..=2 {
+///         writeln!(list, "{i}").unwrap();
+///     }
+/// }
+/// writeln!(w, "];").unwrap();
+/// w.dedent();
+/// writeln!(w, "}}").unwrap();
+///
+/// let expected = cblock!("fn main() {", ["let x = [", clist!("," => ["1", "2"]), "];"], "}");
+/// assert_eq!(Code::from(Concat::new([expected])), w.into_code());
+/// ```
+#[derive(Debug)]
+pub struct CodeWriter {
+    /// The open scopes, from the outermost. The first one is never closed
+    scopes: Vec<Scope>,
+    /// The text written after the last line break
+    line: String,
+    /// The block closed by [`dedent`](Self::dedent), which ends with the next line
+    dedented: Option<Dedented>,
+}
+
+/// Guard of a list opened in a [`CodeWriter`], which closes it when dropped
+///
+/// The items are written through the guard, which dereferences to the writer
+pub struct ListScope<'w> {
+    writer: &'w mut CodeWriter,
+    /// Number of scopes outside of the list
+    depth: usize,
+}
+
+#[derive(Debug)]
+struct Dedented {
+    start: Cow<'static, str>,
+    body: Vec<Code>,
+    /// The line written after the dedent, which is the start of the next block
+    /// if [`indent`](CodeWriter::indent) comes next, and the end otherwise
+    end: Option<String>,
+}
+
+#[derive(Debug)]
+struct Scope {
+    kind: ScopeKind,
+    body: Vec<Code>,
+}
+
+#[derive(Debug)]
+enum ScopeKind {
+    Root,
+    /// Block opened by [`CodeWriter::open_block`]
+    Block {
+        start: Cow<'static, str>,
+        end: Cow<'static, str>,
+    },
+    /// Block opened by [`CodeWriter::indent`], whose end is not known yet
+    Indent {
+        start: Cow<'static, str>,
+    },
+    List {
+        separator: Cow<'static, str>,
+    },
+}
+
+impl Default for CodeWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeWriter {
+    /// Create an empty writer
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![Scope {
+                kind: ScopeKind::Root,
+                body: Vec::new(),
+            }],
+            line: String::new(),
+            dedented: None,
+        }
+    }
+
+    /// Open a block, which is closed with [`close_block`](Self::close_block)
+    pub fn open_block<TStart, TEnd>(&mut self, start: TStart, end: TEnd)
+    where
+        TStart: Into<Cow<'static, str>>,
+        TEnd: Into<Cow<'static, str>>,
+    {
+        self.open(ScopeKind::Block {
+            start: start.into(),
+            end: end.into(),
+        });
+    }
+
+    /// Close the block opened by [`open_block`](Self::open_block)
+    ///
+    /// # Panics
+    /// If the current scope is not a block opened by [`open_block`](Self::open_block)
+    pub fn close_block(&mut self) {
+        self.flush_line();
+        let scope = self.close();
+        let ScopeKind::Block { start, end } = scope.kind else {
+            panic!("close_block called without an open block");
+        };
+        self.push(Block::from_parts(start, Concat::new(scope.body), end));
+    }
+
+    /// Indent the following lines, as the body of a block that starts with the last line.
+    /// The block is closed with [`dedent`](Self::dedent)
+    pub fn indent(&mut self) {
+        self.flush_line();
+        if let Some(Dedented {
+            start,
+            body,
+            end: Some(end),
+        }) = self.dedented.take()
+        {
+            // the line after the dedent starts this block, so the previous block has no end
+            self.push(Block::from_parts(start, Concat::new(body), "").without_end());
+            self.open(ScopeKind::Indent { start: end.into() });
+            return;
+        }
+        self.end_dedented();
+        let start = match self.current().body.last() {
+            Some(Code::Line(_)) => match self.current().body.pop() {
+                Some(Code::Line(line)) => line,
+                _ => unreachable!(),
+            },
+            _ => Cow::Borrowed(""),
+        };
+        self.open(ScopeKind::Indent { start });
+    }
+
+    /// Close the block opened by [`indent`](Self::indent). The next line written is the end
+    /// of the block, unless [`indent`](Self::indent) is called after it, in which case it
+    /// starts the next block. If there is no such line, the block has no end line.
+    /// See [`Block::without_end`]
+    ///
+    /// # Panics
+    /// If the current scope is not a block opened by [`indent`](Self::indent)
+    pub fn dedent(&mut self) {
+        self.flush_line();
+        let scope = self.close();
+        let ScopeKind::Indent { start } = scope.kind else {
+            panic!("dedent called without indent");
+        };
+        self.dedented = Some(Dedented {
+            start,
+            body: scope.body,
+            end: None,
+        });
+    }
+
+    /// Open a list with the separator, where each line is an item.
+    /// The list is closed when the returned guard is dropped
+    pub fn list<TSep: Into<Cow<'static, str>>>(&mut self, separator: TSep) -> ListScope<'_> {
+        self.open(ScopeKind::List {
+            separator: separator.into(),
+        });
+        let depth = self.scopes.len() - 1;
+        ListScope {
+            writer: self,
+            depth,
+        }
+    }
+
+    /// Close all scopes and get the code
+    pub fn into_code(mut self) -> Code {
+        self.close_until(1);
+        let root = self.scopes.pop().expect("root scope");
+        Concat::new(root.body).into()
+    }
+
+    /// Close the scopes until there are `depth` scopes left
+    fn close_until(&mut self, depth: usize) {
+        self.flush_line();
+        while self.scopes.len() > depth {
+            let scope = self.close();
+            let code: Code = match scope.kind {
+                ScopeKind::Block { start, end } => {
+                    Block::from_parts(start, Concat::new(scope.body), end).into()
+                }
+                ScopeKind::Indent { start } => {
+                    Block::from_parts(start, Concat::new(scope.body), "").into()
+                }
+                ScopeKind::List { separator } => {
+                    List::from_parts(separator, Concat::new(scope.body)).into()
+                }
+                ScopeKind::Root => unreachable!(),
+            };
+            self.push(code);
+        }
+        self.end_dedented();
+    }
+
+    fn current(&mut self) -> &mut Scope {
+        self.scopes.last_mut().expect("root scope")
+    }
+
+    fn open(&mut self, kind: ScopeKind) {
+        self.flush_line();
+        self.end_dedented();
+        self.scopes.push(Scope {
+            kind,
+            body: Vec::new(),
+        });
+    }
+
+    fn close(&mut self) -> Scope {
+        self.end_dedented();
+        if self.scopes.len() == 1 {
+            panic!("no open scope to close");
+        }
+        self.scopes.pop().expect("scope")
+    }
+
+    /// Add code to the current scope
+    fn push<T: Into<Code>>(&mut self, code: T) {
+        self.end_dedented();
+        self.current().body.push(code.into());
+    }
+
+    /// Add the block closed by [`dedent`](Self::dedent), with the line written after it
+    /// as the end
+    fn end_dedented(&mut self) {
+        if let Some(Dedented { start, body, end }) = self.dedented.take() {
+            let block = match end {
+                Some(end) => Block::from_parts(start, Concat::new(body), end),
+                None => Block::from_parts(start, Concat::new(body), "").without_end(),
+            };
+            self.current().body.push(block.into());
+        }
+    }
+
+    /// Add the text after the last line break as a line
+    fn flush_line(&mut self) {
+        if !self.line.is_empty() {
+            let line = core::mem::take(&mut self.line);
+            self.push_line(line);
+        }
+    }
+
+    fn push_line(&mut self, line: String) {
+        match &mut self.dedented {
+            // kept until it is known if the line ends the block or starts the next one
+            Some(dedented @ Dedented { end: None, .. }) => dedented.end = Some(line),
+            _ => self.push(line),
+        }
+    }
+}
+
+impl fmt::Write for CodeWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+        if let Some(first) = lines.next() {
+            self.line.push_str(first);
+        }
+        for rest in lines {
+            let line = core::mem::replace(&mut self.line, rest.to_string());
+            self.push_line(line);
+        }
+        Ok(())
+    }
+}
+
+impl From<CodeWriter> for Code {
+    fn from(writer: CodeWriter) -> Self {
+        writer.into_code()
+    }
+}
+
+impl Deref for ListScope<'_> {
+    type Target = CodeWriter;
+
+    fn deref(&self) -> &Self::Target {
+        self.writer
+    }
+}
+
+impl DerefMut for ListScope<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.writer
+    }
+}
+
+impl Drop for ListScope<'_> {
+    fn drop(&mut self) {
+        // blocks opened in the list are closed with it
+        self.writer.close_until(self.depth);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::fmt::Write;
+
+    use indoc::indoc;
+
+    use super::*;
+    use crate::{cblock, cconcat, clist, Format, FormatCode};
+
+    #[test]
+    fn blocks() {
+        let mut w = CodeWriter::new();
+        writeln!(w, "use x;").unwrap();
+        w.open_block("fn main() {", "}");
+        writeln!(w, "if x {{").unwrap();
+        w.indent();
+        writeln!(w, "a();\nb();").unwrap();
+        w.dedent();
+        write!(w, "}}").unwrap();
+        w.close_block();
+        let expected = cconcat![
+            "use x;",
+            cblock!(
+                "fn main() {",
+                [cblock!("if x {", ["a();", "b();"], "}")],
+                "}"
+            ),
+        ];
+        assert_eq!(Code::from(expected), w.into_code());
+    }
+
+    #[test]
+    fn lists() {
+        let mut w = CodeWriter::new();
+        writeln!(w, "f(").unwrap();
+        w.indent();
+        {
+            let mut list = w.list(",");
+            writeln!(list, "a").unwrap();
+            list.open_block("{", "}");
+            writeln!(list, "b").unwrap();
+            list.close_block();
+            // closed with the list
+            list.open_block("[", "]");
+            writeln!(list, "c").unwrap();
+        }
+        w.dedent();
+        writeln!(w, ")").unwrap();
+        let expected = cblock!(
+            "f(",
+            [clist!("," => ["a", cblock!("{", ["b"], "}"), cblock!("[", ["c"], "]")])],
+            ")"
+        );
+        assert_eq!(Code::from(Concat::new([expected])), w.into_code());
+    }
+
+    #[test]
+    fn format() {
+        let mut w = CodeWriter::new();
+        writeln!(w, "f(").unwrap();
+        w.indent();
+        w.list(",").write_str("a\nb\n").unwrap();
+        w.dedent();
+        writeln!(w, ")").unwrap();
+        let code = w.into_code();
+        let expected = indoc! {"
+            f(
+              a,
+              b,
+            )"};
+        assert_eq!(expected, code.format_with(&Format::indent(2)));
+        assert_eq!("f(a,b)", code.format_with(&Format::minify()));
+
+        let Code::Concat(mut concat) = code else {
+            panic!("not a concat");
+        };
+        let Some(Code::Block(block)) = concat.pop() else {
+            panic!("not a block");
+        };
+        let expected = cblock!("f(", [clist!("," => ["a", "b"])], ")");
+        let policy = crate::InlinePolicy::Always;
+        assert_eq!(
+            expected.inline_policy(policy.clone()).to_string(),
+            block.inline_policy(policy).to_string()
+        );
+    }
+
+    #[test]
+    fn unclosed() {
+        let mut w = CodeWriter::new();
+        writeln!(w, "a {{").unwrap();
+        w.indent();
+        write!(w, "b").unwrap();
+        w.dedent();
+        w.open_block("c {", "}");
+        write!(w, "d").unwrap();
+        let expected = indoc! {"
+            a {
+                b
+            c {
+                d
+            }"};
+        assert_eq!(expected, w.into_code().to_string());
+    }
+
+    #[test]
+    fn indent_only() {
+        let mut w = CodeWriter::new();
+        writeln!(w, "if x:").unwrap();
+        w.indent();
+        writeln!(w, "a").unwrap();
+        writeln!(w, "b").unwrap();
+        w.dedent();
+        writeln!(w, "for y in z:").unwrap();
+        w.indent();
+        writeln!(w, "c").unwrap();
+        writeln!(w, "d").unwrap();
+        w.dedent();
+        let expected = cconcat![
+            cblock!("if x:", ["a", "b"], "").without_end(),
+            cblock!("for y in z:", ["c", "d"], "").without_end(),
+        ];
+        let code = w.into_code();
+        assert_eq!(Code::from(expected), code);
+        let expected = indoc! {"
+            if x:
+                a
+                b
+            for y in z:
+                c
+                d"};
+        assert_eq!(expected, code.to_string());
+    }
+}